@@ -0,0 +1,320 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use rocket::{response, Request};
+use rocket::figment::Figment;
+use rocket::figment::providers::{Format, Toml};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+
+use crate::admin::constant_time_eq;
+
+/// Bearer token from the key file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StaticToken {
+    pub token: String,
+    /// Who the token belongs to, the listener limit is shared by everyone using it
+    pub name: String,
+
+    #[serde(default)]
+    pub max_listeners: Option<usize>,
+
+    /// Stations the token is good for, all of them if empty
+    #[serde(default)]
+    pub stations: Vec<String>
+}
+
+/// Key file of the private stations:
+///
+/// ```toml
+/// secret = "long random string"
+///
+/// [[tokens]]
+/// token = "0123456789abcdef"
+/// name = "office"
+/// max_listeners = 5
+/// ```
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct KeyFile {
+    /// Signs the minted tokens, minting is disabled if there is none
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    #[serde(default)]
+    pub tokens: Vec<StaticToken>
+}
+
+/// Payload of a minted token.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Claims {
+    /// Who the token has been minted for
+    pub sub: String,
+    /// Station the token is good for, all of them if none
+    #[serde(default)]
+    pub station: Option<String>,
+    /// Unix time of the expiry, in seconds
+    pub exp: u64,
+    #[serde(default)]
+    pub max: Option<usize>
+}
+
+/// Listener let in by a token.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Grant {
+    /// Listeners sharing the holder share the limit too
+    pub holder: String,
+    pub max_listeners: Option<usize>
+}
+
+/// Why a token has not been accepted.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub enum Denial {
+    Missing,
+    Invalid,
+    Expired,
+    /// The token is for another station
+    Forbidden
+}
+
+/// Validates the tokens of the private stations.
+#[derive(Clone)]
+pub struct Access {
+    secret: Option<Vec<u8>>,
+    tokens: Vec<StaticToken>
+}
+
+impl Access {
+
+    /// Reads the key file at `ACCESS_KEYS_FILE`, if there is one.
+    pub fn load() -> anyhow::Result<Self> {
+        let keys = match std::env::var("ACCESS_KEYS_FILE") {
+            Ok(path) => Figment::from(Toml::file(path)).extract::<KeyFile>()?,
+            Err(_) => KeyFile::default()
+        };
+
+        Ok(Self {
+            secret: keys.secret.map(String::into_bytes),
+            tokens: keys.tokens
+        })
+    }
+
+    /// Signs the claims into a token, `<payload>.<signature>` in URL safe base64.
+    pub fn mint(&self, claims: &Claims) -> anyhow::Result<String> {
+        let secret = self.secret.as_ref().ok_or_else(|| anyhow::Error::msg("no secret configured"))?;
+
+        let payload = base64::encode_config(rocket::serde::json::serde_json::to_vec(claims)?, base64::URL_SAFE_NO_PAD);
+        let signature = base64::encode_config(sign(secret, payload.as_bytes()).finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    /// Checks that the token, either minted or from the key file, is good for the station.
+    pub fn verify(&self, token: Option<&str>, station: &str) -> Result<Grant, Denial> {
+        let token = token.ok_or(Denial::Missing)?;
+
+        if let Some(known) = self.tokens.iter().find(|known| constant_time_eq(known.token.as_bytes(), token.as_bytes())) {
+            if !known.stations.is_empty() && !known.stations.iter().any(|id| id == station) {
+                return Err(Denial::Forbidden);
+            }
+
+            return Ok(Grant {
+                holder: format!("key:{}", known.name),
+                max_listeners: known.max_listeners
+            });
+        }
+
+        let claims = self.decode(token)?;
+        if unix(SystemTime::now()) >= claims.exp {
+            return Err(Denial::Expired);
+        }
+
+        if claims.station.as_ref().is_some_and(|id| id != station) {
+            return Err(Denial::Forbidden);
+        }
+
+        Ok(Grant {
+            holder: format!("sub:{}", claims.sub),
+            max_listeners: claims.max
+        })
+    }
+
+    fn decode(&self, token: &str) -> Result<Claims, Denial> {
+        let secret = self.secret.as_ref().ok_or(Denial::Invalid)?;
+        let (payload, signature) = token.split_once('.').ok_or(Denial::Invalid)?;
+
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| Denial::Invalid)?;
+        sign(secret, payload.as_bytes()).verify_slice(&signature).map_err(|_| Denial::Invalid)?;
+
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| Denial::Invalid)?;
+        rocket::serde::json::serde_json::from_slice(&payload).map_err(|_| Denial::Invalid)
+    }
+}
+
+/// Token request of the admin API.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct MintRequest {
+    pub subject: String,
+    #[serde(default)]
+    pub station: Option<String>,
+    /// In seconds
+    pub expires_in: u64,
+    #[serde(default)]
+    pub max_listeners: Option<usize>
+}
+
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct MintResponse {
+    pub token: String,
+    /// Unix time, in seconds
+    pub expires: u64
+}
+
+impl MintRequest {
+
+    /// Fails if the expiry is too far in the future to be represented.
+    pub fn claims(&self) -> anyhow::Result<Claims> {
+        let exp = SystemTime::now().checked_add(Duration::from_secs(self.expires_in))
+            .ok_or_else(|| anyhow::Error::msg("expires_in is out of range"))?;
+
+        Ok(Claims {
+            sub: self.subject.clone(),
+            station: self.station.clone(),
+            exp: unix(exp),
+            max: self.max_listeners
+        })
+    }
+}
+
+impl<'r> response::Responder<'r, 'static> for Denial {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Err(match self {
+            Denial::Forbidden => Status::Forbidden,
+            _ => Status::Unauthorized
+        })
+    }
+}
+
+/// Token of the request, from the `token` query parameter (for the signed URLs)
+/// or the `Authorization: Bearer <token>` header.
+pub struct Credentials<'r> {
+    access: &'r Access,
+    token: Option<String>
+}
+
+impl<'r> Credentials<'r> {
+
+    pub fn new(access: &'r Access, token: Option<String>) -> Self {
+        Self { access, token }
+    }
+
+    pub fn verify(&self, station: &str) -> Result<Grant, Denial> {
+        self.access.verify(self.token.as_deref(), station)
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Credentials<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let access = match req.rocket().state::<Access>() {
+            Some(access) => access,
+            None => return Outcome::Failure((Status::InternalServerError, ()))
+        };
+
+        let query = req.query_value::<String>("token").and_then(Result::ok);
+        let header = req.headers().get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+
+        Outcome::Success(Credentials {
+            access,
+            token: query.or(header)
+        })
+    }
+}
+
+/// HMAC-SHA256 of the message, keys of any length are accepted.
+fn sign(key: &[u8], message: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac
+}
+
+fn unix(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access() -> Access {
+        Access {
+            secret: Some(b"secret".to_vec()),
+            tokens: Vec::new()
+        }
+    }
+
+    fn claims(station: Option<&str>) -> Claims {
+        Claims {
+            sub: "alice".to_string(),
+            station: station.map(str::to_string),
+            exp: unix(SystemTime::now()) + 60,
+            max: Some(2)
+        }
+    }
+
+    #[test]
+    fn minted_token_is_verified() {
+        let access = access();
+        let token = access.mint(&claims(Some("quartz"))).unwrap();
+
+        let grant = access.verify(Some(&token), "quartz").unwrap();
+        assert_eq!(grant, Grant { holder: "sub:alice".to_string(), max_listeners: Some(2) });
+        assert_eq!(access.verify(Some(&token), "other"), Err(Denial::Forbidden));
+        assert_eq!(access.verify(None, "quartz"), Err(Denial::Missing));
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let access = access();
+        let token = access.mint(&claims(None)).unwrap();
+        let (_, signature) = token.split_once('.').unwrap();
+
+        // 32 bytes of HMAC-SHA256
+        assert_eq!(base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap().len(), 32);
+
+        let forged = base64::encode_config(rocket::serde::json::serde_json::to_vec(&Claims { max: None, ..claims(None) }).unwrap(), base64::URL_SAFE_NO_PAD);
+        assert_eq!(access.verify(Some(&format!("{}.{}", forged, signature)), "quartz"), Err(Denial::Invalid));
+
+        let other = Access { secret: Some(b"other".to_vec()), tokens: Vec::new() };
+        assert_eq!(other.verify(Some(&token), "quartz"), Err(Denial::Invalid));
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let access = access();
+        let token = access.mint(&Claims { exp: unix(SystemTime::now()) - 1, ..claims(None) }).unwrap();
+        assert_eq!(access.verify(Some(&token), "quartz"), Err(Denial::Expired));
+    }
+
+    #[test]
+    fn out_of_range_expiry_is_refused() {
+        let request = MintRequest {
+            subject: "alice".to_string(),
+            station: None,
+            expires_in: u64::MAX,
+            max_listeners: None
+        };
+
+        assert!(request.claims().is_err());
+        assert!(MintRequest { expires_in: 60, ..request }.claims().is_ok());
+    }
+}
@@ -0,0 +1,116 @@
+use rocket::Request;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+
+/// Token required by the admin API, which is disabled if there is none.
+pub struct AdminToken(pub Option<String>);
+
+/// Request guard for the admin API, expects an `Authorization: Bearer <token>` header.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let expected = match req.rocket().state::<AdminToken>().and_then(|token| token.0.as_ref()) {
+            Some(token) => token,
+            None => return Outcome::Failure((Status::Forbidden, ()))
+        };
+
+        let token = req.headers().get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+
+        match token {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Outcome::Success(Admin),
+            _ => Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// Whether the aggregate listening statistics are served without the admin token.
+pub struct PublicStats(pub bool);
+
+/// Request guard for the statistics. Admins get the sessions as well,
+/// everyone else only the aggregates, and only if those are public.
+pub enum StatsAccess {
+    Full,
+    Aggregate
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for StatsAccess {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let public = req.rocket().state::<PublicStats>().is_some_and(|public| public.0);
+
+        // a token is checked even then, so that a wrong one is not silently downgraded
+        if public && req.headers().get_one("Authorization").is_none() {
+            return Outcome::Success(StatsAccess::Aggregate);
+        }
+
+        Admin::from_request(req).await.map(|_| StatsAccess::Full)
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    #[rocket::get("/stats")]
+    fn stats(access: StatsAccess) -> &'static str {
+        match access {
+            StatsAccess::Full => "full",
+            StatsAccess::Aggregate => "aggregate"
+        }
+    }
+
+    fn client(token: Option<&str>, public: bool) -> Client {
+        let rocket = rocket::build()
+            .manage(AdminToken(token.map(str::to_string)))
+            .manage(PublicStats(public))
+            .mount("/", rocket::routes![stats]);
+
+        Client::tracked(rocket).unwrap()
+    }
+
+    fn get(client: &Client, token: Option<&str>) -> (Status, Option<String>) {
+        let mut request = client.get(rocket::uri!(stats));
+        if let Some(token) = token {
+            request = request.header(Header::new("Authorization", format!("Bearer {}", token)));
+        }
+
+        let response = request.dispatch();
+        (response.status(), response.into_string())
+    }
+
+    #[test]
+    fn stats_need_the_admin_token() {
+        let client = client(Some("secret"), false);
+        assert_eq!(get(&client, Some("secret")), (Status::Ok, Some("full".to_string())));
+        assert_eq!(get(&client, Some("wrong")).0, Status::Unauthorized);
+        assert_eq!(get(&client, None).0, Status::Unauthorized);
+
+        let client = self::client(None, false);
+        assert_eq!(get(&client, None).0, Status::Forbidden);
+    }
+
+    #[test]
+    fn public_stats_are_aggregate_only() {
+        let client = client(Some("secret"), true);
+        assert_eq!(get(&client, None), (Status::Ok, Some("aggregate".to_string())));
+        assert_eq!(get(&client, Some("secret")), (Status::Ok, Some("full".to_string())));
+        assert_eq!(get(&client, Some("wrong")).0, Status::Unauthorized);
+
+        let client = self::client(None, true);
+        assert_eq!(get(&client, None), (Status::Ok, Some("aggregate".to_string())));
+    }
+}
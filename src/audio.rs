@@ -1,4 +1,5 @@
 use std::fmt::Debug;
+use std::collections::BTreeMap;
 use serde::{Serialize, Deserialize};
 
 pub trait AudioSource: Send {
@@ -43,9 +44,29 @@ pub struct Track {
     pub audio_url: String
 }
 
+impl Track {
+
+    /// "Author - Title" line, as displayed by the players that only support a single line of metadata.
+    pub fn stream_title(&self) -> Option<String> {
+        match (self.author.as_ref(), self.title.as_ref()) {
+            (Some(author), Some(title)) => Some(format!("{} - {}", author, title)),
+            (Some(author), None) => Some(author.clone()),
+            (None, Some(title)) => Some(title.clone()),
+            (None, None) => None
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
 pub struct Listeners {
-    pub listeners: usize
+    pub listeners: usize,
+
+    #[serde(default)]
+    pub tiers: BTreeMap<String, usize>,
+
+    /// Listeners that are falling behind the broadcast
+    #[serde(default)]
+    pub lagging: usize
 }
 
 /* for testing purposes
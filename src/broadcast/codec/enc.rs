@@ -1,10 +1,11 @@
-use super::{Options, opus, ogg};
-use crate::{AudioFormat, AudioSource};
+use super::{EncoderSettings, Options, Pager, StreamEncoder, opus, ogg, webm, mp4, id3};
+use crate::{AudioFormat, Track};
 use std::ops::Deref;
 use std::time::Duration;
 use bytes::Bytes;
+use rocket::http::ContentType;
 
-/// Encoded audio OGG page (w/ duration info)
+/// Encoded audio page (w/ duration info)
 #[derive(Clone, Eq, PartialEq, Debug, Hash)]
 pub struct Page {
     pub data: Bytes,
@@ -15,69 +16,121 @@ pub struct Encoder {
     opus: opus::OpusEncoder,
     ogg: ogg::OggStream,
     header: Bytes,
-    max_page: Duration,
+    pager: Pager,
+
+    // the last packet is held back, so that it can be marked as the end of the stream on track change
+    held: Vec<u8>,
+    // end of the previous logical stream and the header of the new one
+    chained: Vec<u8>,
 }
 
-/// Audio to OGG-OPUS encoder
+/// Audio to OGG-OPUS encoder.
+/// Every track gets its own chained logical stream, so that players can display the metadata.
 impl Encoder {
 
     pub fn new(format: AudioFormat, options: &Options) -> anyhow::Result<Self> {
-        let mut ogg = ogg::OggStream::new();
+        // a restarted encoder must not reuse the serial of the stream it follows
+        let mut ogg = ogg::OggStream::new(rand::random());
         let opus = opus::OpusEncoder::new(format, options)?;
-        let header = mux_header(&mut ogg, &opus);
+        let header = mux_header(&mut ogg, &opus, None);
 
         Ok(Self {
             opus, ogg,
             header,
-            max_page: options.max_page,
+            pager: Pager::new(format.sample_rate, options.max_page),
+            held: Vec::new(),
+            chained: Vec::new(),
         })
     }
 
-    pub fn header(&self) -> &Bytes {
+    /// Puts the held packet as the last one of the logical stream, and queues up the final pages.
+    fn end_stream(&mut self) {
+        let spp = self.opus.packet_samples();
+
+        if !self.held.is_empty() {
+            self.ogg.finish(&self.held, spp);
+            self.pager.add(spp);
+            self.held.clear();
+        }
+
+        self.ogg.flush();
+        self.chained.extend_from_slice(self.ogg.take().deref());
+    }
+}
+
+impl StreamEncoder for Encoder {
+    fn content_type(&self) -> ContentType {
+        ContentType::new("audio", "ogg")
+    }
+
+    fn header(&self) -> &Bytes {
         &self.header
     }
 
-    pub fn pull<S: AudioSource>(&mut self, mut source: S) -> anyhow::Result<Option<Page>> {
-        let mut samples = 0;
-        let spp = self.opus.frame_size() / self.opus.format().channels as u64;
-        let usps = 1_000_000_000u64 / self.opus.format().sample_rate as u64;
-        let max_smps = self.max_page.as_nanos() as u64 / usps;
+    fn push(&mut self, mut samples: &[f32]) -> anyhow::Result<Option<Page>> {
+        let spp = self.opus.packet_samples();
+
+        while !samples.is_empty() {
+            let (consumed, packet) = self.opus.push(samples)?;
+            samples = &samples[consumed..];
 
-        loop {
-            let exit = match self.opus.pull_page(&mut source)? {
-                Some(page) => {
-                    self.ogg.put(page, spp);
-                    samples += spp;
+            if let Some(packet) = packet {
+                if !self.held.is_empty() {
+                    self.ogg.put(&self.held, spp);
+                    self.pager.add(spp);
 
-                    if samples >= max_smps {
+                    if self.pager.is_full() {
                         self.ogg.flush();
                     }
-
-                    false
-                },
-
-                None => {
-                    self.ogg.flush();
-                    true
                 }
-            };
-
-            let result = self.ogg.take();
-            if !result.is_empty() {
-                return Ok(Some(Page {
-                    data: Bytes::copy_from_slice(result.deref()),
-                    duration: Duration::from_nanos(samples * usps),
-                }))
-            }
 
-            if exit {
-                return Ok(None);
+                self.held.clear();
+                self.held.extend_from_slice(packet);
             }
         }
+
+        let result = self.ogg.take();
+        if result.is_empty() && self.chained.is_empty() {
+            return Ok(None);
+        }
+
+        self.chained.extend_from_slice(result.deref());
+        drop(result);
+
+        Ok(Some(self.pager.cut(Bytes::from(std::mem::take(&mut self.chained)))))
+    }
+
+    fn track(&mut self, track: &Track) {
+        // end the current logical stream...
+        self.end_stream();
+
+        // ...and start a new one with the track's metadata, from a fresh encoder state
+        // so that the new stream is decodable on its own, with the same pre-skip as the first one
+        if let Err(e) = self.opus.reset_state() {
+            eprintln!("opus: failed to reset the encoder: {}", e);
+        }
+
+        self.ogg = ogg::OggStream::new(rand::random());
+        self.header = mux_header(&mut self.ogg, &self.opus, Some(track));
+        self.chained.extend_from_slice(&self.header);
+    }
+
+    fn finish(&mut self) -> anyhow::Result<Option<Page>> {
+        self.end_stream();
+
+        if self.chained.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(self.pager.cut(Bytes::from(std::mem::take(&mut self.chained)))))
+    }
+
+    fn configure(&mut self, settings: &EncoderSettings) -> anyhow::Result<EncoderSettings> {
+        self.opus.configure(settings)
     }
 }
 
-fn mux_header(ogg: &mut ogg::OggStream, encoder: &opus::OpusEncoder) -> Bytes {
+fn mux_header(ogg: &mut ogg::OggStream, encoder: &opus::OpusEncoder, track: Option<&Track>) -> Bytes {
     let mut buffer = Vec::new();
 
     let _ = encoder.write_header(&mut buffer);
@@ -85,9 +138,232 @@ fn mux_header(ogg: &mut ogg::OggStream, encoder: &opus::OpusEncoder) -> Bytes {
     ogg.flush();
     buffer.clear();
 
-    let _ = encoder.write_tags(&mut buffer);
+    let _ = encoder.write_tags(&mut buffer, track);
     ogg.put(&buffer, 0);
     ogg.flush();
 
     Bytes::copy_from_slice(ogg.take().deref())
-}
\ No newline at end of file
+}
+
+/// Audio to WebM-OPUS encoder
+pub struct WebmEncoder {
+    opus: opus::OpusEncoder,
+    webm: webm::WebmStream,
+    header: Bytes,
+    pager: Pager,
+}
+
+impl WebmEncoder {
+
+    pub fn new(format: AudioFormat, options: &Options) -> anyhow::Result<Self> {
+        let opus = opus::OpusEncoder::new(format, options)?;
+        let webm = webm::WebmStream::new(format);
+
+        let mut opus_head = Vec::new();
+        opus.write_header(&mut opus_head)?;
+        let header = Bytes::from(webm.header(&opus_head, opus.pre_skip()));
+
+        Ok(Self {
+            opus, webm,
+            header,
+            pager: Pager::new(format.sample_rate, options.max_page),
+        })
+    }
+}
+
+impl StreamEncoder for WebmEncoder {
+    fn content_type(&self) -> ContentType {
+        ContentType::new("audio", "webm")
+    }
+
+    fn header(&self) -> &Bytes {
+        &self.header
+    }
+
+    fn push(&mut self, mut samples: &[f32]) -> anyhow::Result<Option<Page>> {
+        let spp = self.opus.packet_samples();
+
+        while !samples.is_empty() {
+            let (consumed, packet) = self.opus.push(samples)?;
+            samples = &samples[consumed..];
+
+            if let Some(packet) = packet {
+                self.webm.put(packet, spp);
+                self.pager.add(spp);
+            }
+        }
+
+        if !self.pager.is_full() {
+            return Ok(None);
+        }
+
+        let pager = &mut self.pager;
+        Ok(self.webm.take().map(|cluster| pager.cut(Bytes::from(cluster))))
+    }
+
+    fn configure(&mut self, settings: &EncoderSettings) -> anyhow::Result<EncoderSettings> {
+        self.opus.configure(settings)
+    }
+}
+
+/// Audio to fragmented MP4-OPUS encoder.
+/// Header is the init segment, every page is a media segment.
+pub struct Fmp4Encoder {
+    opus: opus::OpusEncoder,
+    mp4: mp4::Fmp4Stream,
+    header: Bytes,
+    pager: Pager,
+}
+
+const ID3_SCHEME: &str = "https://aomedia.org/emsg/ID3";
+
+impl Fmp4Encoder {
+
+    pub fn new(format: AudioFormat, options: &Options) -> anyhow::Result<Self> {
+        let opus = opus::OpusEncoder::new(format, options)?;
+        let mp4 = mp4::Fmp4Stream::new(format);
+        let header = Bytes::from(mp4.init(opus.pre_skip(), opus.output_gain()));
+
+        Ok(Self {
+            opus, mp4,
+            header,
+            pager: Pager::new(format.sample_rate, options.max_page),
+        })
+    }
+}
+
+impl StreamEncoder for Fmp4Encoder {
+    fn content_type(&self) -> ContentType {
+        ContentType::new("audio", "mp4")
+    }
+
+    fn header(&self) -> &Bytes {
+        &self.header
+    }
+
+    fn push(&mut self, mut samples: &[f32]) -> anyhow::Result<Option<Page>> {
+        let spp = self.opus.packet_samples();
+
+        while !samples.is_empty() {
+            let (consumed, packet) = self.opus.push(samples)?;
+            samples = &samples[consumed..];
+
+            if let Some(packet) = packet {
+                self.mp4.put(packet, spp);
+                self.pager.add(spp);
+            }
+        }
+
+        if !self.pager.is_full() {
+            return Ok(None);
+        }
+
+        let pager = &mut self.pager;
+        Ok(self.mp4.take().map(|segment| pager.cut(Bytes::from(segment))))
+    }
+
+    fn track(&mut self, track: &Track) {
+        self.mp4.event(ID3_SCHEME, "", &id3::tag(track));
+    }
+
+    fn configure(&mut self, settings: &EncoderSettings) -> anyhow::Result<EncoderSettings> {
+        self.opus.configure(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ogg_tests, test_options};
+    use super::super::ogg_tests::{BOS, EOS};
+    use std::convert::TryInto;
+    use audiopus::coder::Decoder;
+
+    const FORMAT: AudioFormat = AudioFormat {
+        channels: 2,
+        sample_rate: 48000
+    };
+
+    /// Second of a 440 Hz tone starting at the second `start`, interleaved stereo.
+    fn tone(start: usize) -> Vec<f32> {
+        (start * 48000..(start + 1) * 48000)
+            .flat_map(|i| {
+                let sample = (i as f32 * 440.0 * std::f32::consts::TAU / 48000.0).sin() * 0.5;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    /// Decodes the packets of a logical stream, dropping the pre-skip.
+    fn decode(packets: &[Vec<u8>]) -> Vec<f32> {
+        let pre_skip = u16::from_le_bytes(packets[0][10..12].try_into().unwrap()) as usize;
+        let mut decoder = Decoder::new(audiopus::SampleRate::Hz48000, audiopus::Channels::Stereo).unwrap();
+        let mut output = vec![0.0; 2 * 5760];
+        let mut decoded = Vec::new();
+
+        for packet in &packets[2..] {
+            let samples = decoder.decode_float(Some(packet.as_slice().try_into().unwrap()), (&mut output).try_into().unwrap(), false).unwrap();
+            decoded.extend_from_slice(&output[..2 * samples]);
+        }
+
+        decoded.split_off(2 * pre_skip)
+    }
+
+    #[test]
+    fn track_chains_a_new_logical_stream() {
+        let mut encoder = Encoder::new(FORMAT, &test_options(Duration::from_millis(200))).unwrap();
+        let mut data = encoder.header().to_vec();
+
+        let mut pages = vec![encoder.push(&tone(0)).unwrap()];
+        encoder.track(&Track {
+            title: Some("Title".to_owned()),
+            subtitle: None,
+            author: None,
+            source_url: None,
+            background_url: None,
+            audio_url: "track.ogg".to_owned()
+        });
+        pages.push(encoder.push(&tone(1)).unwrap());
+        pages.push(encoder.finish().unwrap());
+
+        for page in pages.into_iter().flatten() {
+            data.extend_from_slice(&page.data);
+        }
+
+        let pages = ogg_tests::pages(&data);
+        let ends: Vec<usize> = (0..pages.len()).filter(|&i| pages[i].flags & EOS != 0).collect();
+        assert_eq!(ends.len(), 2);
+        assert_eq!(ends[1], pages.len() - 1);
+
+        // the first stream ends with all its samples, right before the new one begins
+        let (end, next) = (pages[ends[0]], pages[ends[0] + 1]);
+        assert_eq!(end.serial, pages[0].serial);
+        assert_eq!(end.granule, 48000);
+        assert_eq!(next.flags, BOS);
+        assert_ne!(next.serial, end.serial);
+        assert_eq!(next.sequence, 0);
+        assert!(pages[ends[0] + 1..].iter().all(|page| page.serial == next.serial));
+
+        // the new stream is exactly what a fresh encoder makes of the same audio...
+        let link = ogg_tests::packets(&data, next.serial);
+        assert_eq!(&link[0][..8], b"OpusHead");
+
+        let mut fresh = Encoder::new(FORMAT, &test_options(Duration::from_millis(200))).unwrap();
+        let mut fresh_data = fresh.header().to_vec();
+        for page in [fresh.push(&tone(1)).unwrap(), fresh.finish().unwrap()].iter().flatten() {
+            fresh_data.extend_from_slice(&page.data);
+        }
+
+        let fresh_packets = ogg_tests::packets(&fresh_data, ogg_tests::pages(&fresh_data)[0].serial);
+        assert_eq!(link[0], fresh_packets[0]);
+        assert_eq!(link[2..], fresh_packets[2..]);
+
+        // ...so it decodes on its own, in line with the input once the pre-skip is dropped
+        let decoded = decode(&link);
+        let input = tone(1);
+        let length = decoded.len().min(input.len()) - 2 * 960;
+        let error: f32 = decoded[..length].iter().zip(&input[..length]).map(|(a, b)| (a - b).powi(2)).sum();
+        let signal: f32 = input[..length].iter().map(|a| a.powi(2)).sum();
+        assert!(error / signal < 0.01, "error ratio {}", error / signal);
+    }
+}
@@ -0,0 +1,394 @@
+use super::{FlacOptions, Page, Pager, StreamEncoder};
+use crate::AudioFormat;
+use bytes::{Bytes, BytesMut};
+use rocket::http::ContentType;
+
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_RICE_PARAMETER: u32 = 14;
+
+struct BitWriter {
+    buffer: Vec<u8>,
+    acc: u64,
+    bits: u32
+}
+
+impl BitWriter {
+
+    fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            acc: 0,
+            bits: 0
+        }
+    }
+
+    fn write(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            self.acc = (self.acc << 1) | (value >> i & 1);
+            self.bits += 1;
+
+            if self.bits == 8 {
+                self.buffer.push(self.acc as u8);
+                self.acc = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64 & ((1 << bits) - 1), bits);
+    }
+
+    fn write_unary(&mut self, zeros: u64) {
+        for _ in 0..zeros {
+            self.write(0, 1);
+        }
+
+        self.write(1, 1);
+    }
+
+    /// Pads the stream with zeros up to the byte boundary.
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    fn into_inner(mut self) -> Vec<u8> {
+        self.align();
+        self.buffer
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+
+        crc
+    })
+}
+
+/// Frame number coded the same way as UTF-8 code points.
+fn write_coded_number(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+
+    let mut bytes = 2;
+    while bytes < 7 && value >= 1 << (5 * bytes + 1) {
+        bytes += 1;
+    }
+
+    let lead = (0xFF00u64 >> bytes) & 0xFF;
+    writer.write(lead | value >> (6 * (bytes - 1)), 8);
+
+    for i in (0..bytes - 1).rev() {
+        writer.write(0x80 | (value >> (6 * i) & 0x3F), 8);
+    }
+}
+
+fn residual(samples: &[i64], order: usize) -> impl Iterator<Item = i64> + '_ {
+    samples.windows(order + 1).map(move |w| {
+        let s = |i: usize| w[order - i];
+
+        match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - 2 * s(1) + s(2),
+            3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+            _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4)
+        }
+    })
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn rice_size(residual: &[u64], parameter: u32) -> u64 {
+    residual.iter().map(|&r| (r >> parameter) + 1 + parameter as u64).sum()
+}
+
+/// Writes a FIXED subframe, choosing the predictor order and the rice parameter that yield the smallest output.
+fn write_subframe(writer: &mut BitWriter, samples: &[i64]) {
+    let order_limit = MAX_FIXED_ORDER.min(samples.len().saturating_sub(1));
+
+    let mut best: Option<(u64, usize, u32, Vec<u64>)> = None;
+
+    for order in 0..=order_limit {
+        let residual: Vec<u64> = residual(samples, order).map(zigzag).collect();
+        let (size, parameter) = (0..=MAX_RICE_PARAMETER)
+            .map(|parameter| (rice_size(&residual, parameter), parameter))
+            .min()
+            .expect("no rice parameters");
+
+        let size = size + order as u64 * BITS_PER_SAMPLE as u64;
+        if best.as_ref().is_none_or(|(best_size, ..)| size < *best_size) {
+            best = Some((size, order, parameter, residual));
+        }
+    }
+
+    let (_, order, parameter, residual) = best.expect("empty block");
+
+    writer.write(0, 1);                             // padding
+    writer.write(0b001000 | order as u64, 6);       // type: FIXED
+    writer.write(0, 1);                             // no wasted bits
+
+    for &sample in samples[..order].iter() {
+        writer.write_signed(sample, BITS_PER_SAMPLE);
+    }
+
+    writer.write(0, 2);                             // residual coding: rice, 4-bit parameters
+    writer.write(0, 4);                             // partition order
+    writer.write(parameter as u64, 4);
+
+    for &r in residual.iter() {
+        writer.write_unary(r >> parameter);
+        writer.write(r & ((1 << parameter) - 1), parameter);
+    }
+}
+
+/// Audio to native FLAC encoder (16 bit, fixed predictors)
+pub struct FlacEncoder {
+    format: AudioFormat,
+    header: Bytes,
+    block: Vec<f32>,
+    block_filled: usize,
+    frame_number: u64,
+    buffer: BytesMut,
+    pager: Pager,
+}
+
+impl FlacEncoder {
+
+    pub fn new(format: AudioFormat, options: &FlacOptions) -> anyhow::Result<Self> {
+        if format.channels == 0 || format.channels > 8 {
+            return Err(anyhow::Error::msg(format!("flac: unsupported number of channels: {}", format.channels)));
+        }
+
+        if options.block_size < 16 {
+            return Err(anyhow::Error::msg("flac: block size should be at least 16"));
+        }
+
+        Ok(Self {
+            format,
+            header: Bytes::from(stream_header(format, options.block_size)),
+            block: vec![0.0; options.block_size as usize * format.channels as usize],
+            block_filled: 0,
+            frame_number: 0,
+            buffer: BytesMut::new(),
+            pager: Pager::new(format.sample_rate, options.max_page),
+        })
+    }
+
+    fn write_frame(&mut self) {
+        let channels = self.format.channels as usize;
+        let block_size = self.block.len() / channels;
+
+        let mut writer = BitWriter::new();
+        writer.write(0xFFF8, 16);                                   // sync code, fixed block size
+        writer.write(0b0111, 4);                                    // block size: 16 bit at the end of the header
+        writer.write(0b0000, 4);                                    // sample rate: from STREAMINFO
+        writer.write(channels as u64 - 1, 4);                       // channel assignment: independent
+        writer.write(0b100, 3);                                     // sample size: 16 bit
+        writer.write(0, 1);                                         // reserved
+        write_coded_number(&mut writer, self.frame_number);
+        writer.write(block_size as u64 - 1, 16);
+
+        let mut frame = writer.into_inner();
+        frame.push(crc8(&frame));
+
+        let mut writer = BitWriter::new();
+        for channel in 0..channels {
+            let samples: Vec<i64> = self.block.iter()
+                .skip(channel)
+                .step_by(channels)
+                .map(|&s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i64)
+                .collect();
+
+            write_subframe(&mut writer, &samples);
+        }
+
+        frame.append(&mut writer.into_inner());
+        frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+
+        self.frame_number += 1;
+        self.buffer.extend_from_slice(&frame);
+    }
+}
+
+fn stream_header(format: AudioFormat, block_size: u16) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+
+    writer.write(1, 1);                                 // last metadata block
+    writer.write(0, 7);                                 // type: STREAMINFO
+    writer.write(34, 24);                               // length
+    writer.write(block_size as u64, 16);                // min block size
+    writer.write(block_size as u64, 16);                // max block size
+    writer.write(0, 24);                                // min frame size (unknown)
+    writer.write(0, 24);                                // max frame size (unknown)
+    writer.write(format.sample_rate as u64, 20);
+    writer.write(format.channels as u64 - 1, 3);
+    writer.write(BITS_PER_SAMPLE as u64 - 1, 5);
+    writer.write(0, 36);                                // total samples (unknown)
+    writer.write(0, 64);                                // md5 (unknown)
+    writer.write(0, 64);
+
+    let mut header = b"fLaC".to_vec();
+    header.append(&mut writer.into_inner());
+    header
+}
+
+impl StreamEncoder for FlacEncoder {
+    fn content_type(&self) -> ContentType {
+        ContentType::new("audio", "flac")
+    }
+
+    fn header(&self) -> &Bytes {
+        &self.header
+    }
+
+    fn push(&mut self, mut samples: &[f32]) -> anyhow::Result<Option<Page>> {
+        let spp = (self.block.len() / self.format.channels as usize) as u64;
+
+        while !samples.is_empty() {
+            let consumed = samples.len().min(self.block.len() - self.block_filled);
+            self.block[self.block_filled..self.block_filled + consumed].copy_from_slice(&samples[..consumed]);
+            self.block_filled += consumed;
+            samples = &samples[consumed..];
+
+            if self.block_filled == self.block.len() {
+                self.block_filled = 0;
+                self.write_frame();
+                self.pager.add(spp);
+            }
+        }
+
+        if !self.pager.is_full() {
+            return Ok(None);
+        }
+
+        Ok(Some(self.pager.cut(self.buffer.split().freeze())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::Duration;
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::formats::{FormatOptions, FormatReader};
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::default::formats::FlacReader;
+
+    #[test]
+    fn crcs_match_the_check_values() {
+        // CRC-8/SMBUS and CRC-16/UMTS, the parameters FLAC uses
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+        assert_eq!(crc8(b""), 0);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn bit_writer_packs_msb_first() {
+        let mut writer = BitWriter::new();
+        writer.write(0b101, 3);
+        writer.write_signed(-1, 4);
+        writer.write_unary(2);
+        writer.write(0xABC, 12);
+
+        assert_eq!(writer.into_inner(), [0b1011_1110, 0b0110_1010, 0b1111_0000]);
+    }
+
+    #[test]
+    fn frame_numbers_are_coded_like_utf8() {
+        for value in [0u64, 0x7F, 0x80, 0x7FF, 0x800, 0xFFFF, 0x10000, 0x10FFFF] {
+            let mut writer = BitWriter::new();
+            write_coded_number(&mut writer, value);
+
+            let expected = char::from_u32(value as u32).map_or_else(Vec::new, |c| c.to_string().into_bytes());
+            assert_eq!(writer.into_inner(), expected, "{:#x}", value);
+        }
+
+        // beyond the code points, up to 36 bits
+        let mut writer = BitWriter::new();
+        write_coded_number(&mut writer, 0xFFFFFFFFF);
+        assert_eq!(writer.into_inner(), [0xFE, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]);
+    }
+
+    #[test]
+    fn encoded_stream_decodes_losslessly() {
+        let format = AudioFormat {
+            channels: 2,
+            sample_rate: 44100
+        };
+
+        let mut encoder = FlacEncoder::new(format, &FlacOptions {
+            block_size: 1152,
+            max_page: Duration::ZERO,
+            buffer_size: Duration::from_secs(1)
+        }).unwrap();
+
+        // a tone on the left, noise and clipping on the right, then silence
+        let frames = 1152 * 3;
+        let samples: Vec<f32> = (0..frames)
+            .flat_map(|i| {
+                let left = (i as f32 * 0.05).sin() * 0.8;
+                let right = match i {
+                    _ if i < 1152 => rand::random::<f32>() * 2.0 - 1.0,
+                    _ if i < 2304 => if i % 2 == 0 { 1.5 } else { -1.5 },
+                    _ => 0.0
+                };
+
+                [left, right]
+            })
+            .collect();
+
+        let mut data = encoder.header().to_vec();
+        for chunk in samples.chunks(1000) {
+            if let Some(page) = encoder.push(chunk).unwrap() {
+                data.extend_from_slice(&page.data);
+            }
+        }
+
+        let source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+        let mut reader = FlacReader::try_new(source, &FormatOptions::default()).expect("invalid header");
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&reader.tracks()[0].codec_params, &DecoderOptions { verify: true })
+            .expect("no decoder");
+
+        let mut decoded = Vec::new();
+        while let Ok(packet) = reader.next_packet() {
+            let buffer = decoder.decode(&packet).expect("invalid frame");
+            let mut interleaved = SampleBuffer::<i16>::new(buffer.capacity() as u64, *buffer.spec());
+            interleaved.copy_interleaved_ref(buffer);
+            decoded.extend_from_slice(interleaved.samples());
+        }
+
+        let expected: Vec<i16> = samples.iter()
+            .map(|&s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            .collect();
+
+        assert_eq!(decoded.len(), expected.len());
+        assert!(decoded == expected, "decoded samples differ");
+    }
+}
@@ -0,0 +1,43 @@
+use crate::Track;
+
+fn syncsafe(value: u32) -> [u8; 4] {
+    [
+        (value >> 21 & 0x7F) as u8,
+        (value >> 14 & 0x7F) as u8,
+        (value >> 7 & 0x7F) as u8,
+        (value & 0x7F) as u8
+    ]
+}
+
+fn write_text_frame(buffer: &mut Vec<u8>, id: &[u8; 4], text: &str) {
+    buffer.extend_from_slice(id);
+    buffer.extend_from_slice(&syncsafe(text.len() as u32 + 1));
+    buffer.extend_from_slice(&[0, 0]);  // flags
+    buffer.push(3);                     // encoding: utf-8
+    buffer.extend_from_slice(text.as_bytes());
+}
+
+/// Builds an ID3v2.4 tag with the track's title and author.
+pub fn tag(track: &Track) -> Vec<u8> {
+    let mut frames = Vec::new();
+
+    if let Some(title) = track.title.as_ref() {
+        write_text_frame(&mut frames, b"TIT2", title);
+    }
+
+    if let Some(author) = track.author.as_ref() {
+        write_text_frame(&mut frames, b"TPE1", author);
+    }
+
+    if let Some(subtitle) = track.subtitle.as_ref() {
+        write_text_frame(&mut frames, b"TIT3", subtitle);
+    }
+
+    let mut buffer = Vec::with_capacity(frames.len() + 10);
+    buffer.extend_from_slice(b"ID3");
+    buffer.extend_from_slice(&[4, 0]);  // version 2.4.0
+    buffer.push(0);                     // flags
+    buffer.extend_from_slice(&syncsafe(frames.len() as u32));
+    buffer.append(&mut frames);
+    buffer
+}
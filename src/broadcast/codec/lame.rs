@@ -0,0 +1,152 @@
+use std::os::raw::{c_int, c_uchar, c_float, c_void};
+use crate::AudioFormat;
+
+#[allow(non_camel_case_types)]
+type lame_t = *mut c_void;
+
+#[link(name = "mp3lame")]
+extern "C" {
+    fn lame_init() -> lame_t;
+    fn lame_close(gfp: lame_t) -> c_int;
+    fn lame_init_params(gfp: lame_t) -> c_int;
+
+    fn lame_set_num_channels(gfp: lame_t, channels: c_int) -> c_int;
+    fn lame_set_in_samplerate(gfp: lame_t, rate: c_int) -> c_int;
+    fn lame_set_out_samplerate(gfp: lame_t, rate: c_int) -> c_int;
+    fn lame_set_brate(gfp: lame_t, brate: c_int) -> c_int;
+    fn lame_set_VBR(gfp: lame_t, mode: c_int) -> c_int;
+    fn lame_set_quality(gfp: lame_t, quality: c_int) -> c_int;
+    fn lame_set_bWriteVbrTag(gfp: lame_t, write: c_int) -> c_int;
+
+    fn lame_encode_buffer_ieee_float(
+        gfp: lame_t,
+        pcm_l: *const c_float,
+        pcm_r: *const c_float,
+        nsamples: c_int,
+        mp3buf: *mut c_uchar,
+        mp3buf_size: c_int
+    ) -> c_int;
+
+    fn lame_encode_buffer_interleaved_ieee_float(
+        gfp: lame_t,
+        pcm: *const c_float,
+        nsamples: c_int,
+        mp3buf: *mut c_uchar,
+        mp3buf_size: c_int
+    ) -> c_int;
+
+    fn lame_encode_flush(gfp: lame_t, mp3buf: *mut c_uchar, size: c_int) -> c_int;
+}
+
+const VBR_OFF: c_int = 0;
+
+/// Thin wrapper around the LAME encoder (CBR only).
+pub struct LameEncoder {
+    lame: lame_t,
+    format: AudioFormat,
+    buffer: Vec<u8>
+}
+
+unsafe impl Send for LameEncoder {}
+unsafe impl Sync for LameEncoder {}
+
+//literally c (again)
+impl LameEncoder {
+
+    pub fn new(format: AudioFormat, bit_rate: u32, quality: u8) -> anyhow::Result<Self> {
+        if format.channels != 1 && format.channels != 2 {
+            return Err(anyhow::Error::msg(format!("lame: unsupported number of channels: {}", format.channels)));
+        }
+
+        unsafe {
+            let lame = lame_init();
+            if lame.is_null() {
+                return Err(anyhow::Error::msg("lame: failed to initialize"));
+            }
+
+            let encoder = Self {
+                lame,
+                format,
+                buffer: Vec::new()
+            };
+
+            let status = [
+                lame_set_num_channels(lame, format.channels as c_int),
+                lame_set_in_samplerate(lame, format.sample_rate as c_int),
+                lame_set_out_samplerate(lame, format.sample_rate as c_int),
+                lame_set_VBR(lame, VBR_OFF),
+                lame_set_brate(lame, bit_rate as c_int),
+                lame_set_quality(lame, quality as c_int),
+                lame_set_bWriteVbrTag(lame, 0),
+                lame_init_params(lame)
+            ];
+
+            if status.iter().any(|&s| s < 0) {
+                return Err(anyhow::Error::msg("lame: invalid encoder parameters"));
+            }
+
+            Ok(encoder)
+        }
+    }
+
+    pub fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    /// Encodes interleaved samples, returning whatever MP3 frames got completed.
+    pub fn encode(&mut self, samples: &[f32]) -> anyhow::Result<&[u8]> {
+        let frames = samples.len() / self.format.channels as usize;
+
+        // worst case estimate as per lame.h
+        self.buffer.resize(frames * 5 / 4 + 7200, 0);
+
+        let written = unsafe {
+            if self.format.channels == 1 {
+                lame_encode_buffer_ieee_float(
+                    self.lame,
+                    samples.as_ptr(),
+                    samples.as_ptr(),
+                    frames as c_int,
+                    self.buffer.as_mut_ptr(),
+                    self.buffer.len() as c_int)
+            } else {
+                lame_encode_buffer_interleaved_ieee_float(
+                    self.lame,
+                    samples.as_ptr(),
+                    frames as c_int,
+                    self.buffer.as_mut_ptr(),
+                    self.buffer.len() as c_int)
+            }
+        };
+
+        if written < 0 {
+            return Err(anyhow::Error::msg(format!("lame: encoding error {}", written)));
+        }
+
+        Ok(&self.buffer[..written as usize])
+    }
+
+    /// Encodes the samples still buffered by LAME, padding the last frame with silence.
+    pub fn flush(&mut self) -> anyhow::Result<&[u8]> {
+        // at least 7200 bytes as per lame.h
+        self.buffer.resize(7200, 0);
+
+        let written = unsafe {
+            lame_encode_flush(self.lame, self.buffer.as_mut_ptr(), self.buffer.len() as c_int)
+        };
+
+        if written < 0 {
+            return Err(anyhow::Error::msg(format!("lame: flushing error {}", written)));
+        }
+
+        Ok(&self.buffer[..written as usize])
+    }
+}
+
+impl Drop for LameEncoder {
+    fn drop(&mut self) {
+        unsafe {
+            lame_close(self.lame);
+        }
+    }
+}
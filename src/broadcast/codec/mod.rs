@@ -1,9 +1,94 @@
 mod opus;
 mod ogg;
+mod webm;
+mod mp4;
+mod id3;
 mod enc;
+#[cfg(feature = "mp3")]
+mod lame;
+#[cfg(feature = "mp3")]
+mod mp3;
+mod flac;
+mod packets;
 
 pub use enc::*;
+#[cfg(feature = "mp3")]
+pub use mp3::*;
+pub use flac::*;
+pub use packets::*;
+#[cfg(test)]
+pub(crate) use ogg::tests as ogg_tests;
 use std::time::Duration;
+use bytes::Bytes;
+use rocket::http::ContentType;
+use serde::{Serialize, Deserialize};
+use crate::{AudioFormat, Track};
+
+/// Live audio stream encoder.
+pub trait StreamEncoder: Send {
+
+    /// Content type of the produced stream.
+    fn content_type(&self) -> ContentType;
+
+    /// Data every listener must receive before any of the pages.
+    fn header(&self) -> &Bytes;
+
+    /// Encodes the samples, returning the page if one got completed.
+    fn push(&mut self, samples: &[f32]) -> anyhow::Result<Option<Page>>;
+
+    /// Notifies the encoder that a new track has started playing.
+    fn track(&mut self, _track: &Track) {}
+
+    /// Ends the stream, returning the remaining data along with the end-of-stream marker, if the format has one.
+    fn finish(&mut self) -> anyhow::Result<Option<Page>> {
+        Ok(None)
+    }
+
+    /// Changes the encoder settings in place, returning the resulting settings.
+    fn configure(&mut self, _settings: &EncoderSettings) -> anyhow::Result<EncoderSettings> {
+        Err(anyhow::Error::msg("encoder settings can not be changed for this format"))
+    }
+}
+
+/// Frame accounting shared by the encoders: counts the samples (per channel) encoded
+/// since the last page, and tells when there are enough of them to cut the next one.
+struct Pager {
+    sample_rate: u64,
+    max_samples: u64,
+    pending: u64
+}
+
+impl Pager {
+
+    fn new(sample_rate: u32, max_page: Duration) -> Self {
+        Self {
+            sample_rate: sample_rate as u64,
+            max_samples: (max_page.as_nanos() * sample_rate as u128 / 1_000_000_000) as u64,
+            pending: 0
+        }
+    }
+
+    fn add(&mut self, samples: u64) {
+        self.pending += samples;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending == 0
+    }
+
+    /// Whether the page being built is long enough to be sent.
+    fn is_full(&self) -> bool {
+        self.pending >= self.max_samples
+    }
+
+    /// Cuts the page out of the data built so far, and starts the next one.
+    fn cut(&mut self, data: Bytes) -> Page {
+        let duration = Duration::from_nanos(self.pending * 1_000_000_000 / self.sample_rate);
+        self.pending = 0;
+
+        Page { data, duration }
+    }
+}
 
 #[derive(Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Debug, Hash)]
 pub enum FrameSize {
@@ -32,6 +117,7 @@ pub type Bitrate = audiopus::Bitrate;
 pub type Signal = audiopus::Signal;
 pub type Application = audiopus::Application;
 pub type Bandwidth = audiopus::Bandwidth;
+pub type Channels = audiopus::Channels;
 
 #[derive(Clone, Eq, PartialEq, Debug, Hash)]
 pub struct Options {
@@ -43,5 +129,330 @@ pub struct Options {
     pub max_page: Duration,
     pub buffer_size: Duration,
     pub complexity: u8,
-    pub vbr: bool
-}
\ No newline at end of file
+    pub vbr: bool,
+    /// Keeps the bitrate close to the target in VBR mode
+    pub vbr_constraint: bool,
+    /// In-band forward error correction, only used when `packet_loss` is non-zero
+    pub fec: bool,
+    /// Expected packet loss, in percent
+    pub packet_loss: u8,
+    /// Discontinuous transmission, reduces the bitrate during silence
+    pub dtx: bool,
+    /// Depth of the input signal, 8 to 24 bits
+    pub lsb_depth: u8,
+    /// Forces mono or stereo coding, `Auto` lets the encoder decide
+    pub force_channels: Channels,
+    /// Disables inter-frame prediction, making every frame independently decodable
+    pub prediction_disabled: bool,
+    /// Gain applied by the decoder, in Q7.8 dB (1/256 dB steps)
+    pub output_gain: i16
+}
+
+/// Opus encoder settings that can be changed while the stream is running.
+/// Missing fields are left unchanged.
+#[derive(Serialize, Deserialize, Clone, Default, Eq, PartialEq, Debug, Hash)]
+pub struct EncoderSettings {
+
+    /// Bitrate in bits per second
+    #[serde(default)]
+    pub bit_rate: Option<i32>,
+
+    /// 0 (fastest) to 10 (best)
+    #[serde(default)]
+    pub complexity: Option<u8>,
+
+    /// `auto`, `narrowband`, `mediumband`, `wideband`, `superwideband` or `fullband`
+    #[serde(default)]
+    pub bandwidth: Option<String>,
+
+    /// `auto`, `voice` or `music`
+    #[serde(default)]
+    pub signal: Option<String>,
+
+    /// Depth of the input signal, 8 to 24 bits
+    #[serde(default)]
+    pub lsb_depth: Option<u8>,
+
+    /// `auto`, `mono` or `stereo`, the latter needs a stereo input
+    #[serde(default)]
+    pub force_channels: Option<String>
+}
+
+impl EncoderSettings {
+
+    pub fn from_options(options: &Options) -> Self {
+        Self {
+            bit_rate: match options.bit_rate {
+                Bitrate::BitsPerSecond(bits) => Some(bits),
+                _ => None
+            },
+            complexity: Some(options.complexity),
+            bandwidth: Some(bandwidth_name(options.bandwidth).to_string()),
+            signal: Some(signal_name(options.signal).to_string()),
+            lsb_depth: Some(options.lsb_depth),
+            force_channels: Some(channels_name(options.force_channels).to_string())
+        }
+    }
+
+    /// Checks the settings against the ranges of libopus and the channels of the input.
+    pub fn validate(&self, format: AudioFormat) -> anyhow::Result<()> {
+        if let Some(bit_rate) = self.bit_rate {
+            if !(500..=512000).contains(&bit_rate) {
+                return Err(anyhow::Error::msg("bit_rate must be between 500 and 512000"));
+            }
+        }
+
+        if let Some(complexity) = self.complexity {
+            if complexity > 10 {
+                return Err(anyhow::Error::msg("complexity must be between 0 and 10"));
+            }
+        }
+
+        if let Some(bandwidth) = &self.bandwidth {
+            parse_bandwidth(bandwidth)?;
+        }
+
+        if let Some(signal) = &self.signal {
+            parse_signal(signal)?;
+        }
+
+        if let Some(lsb_depth) = self.lsb_depth {
+            if !(8..=24).contains(&lsb_depth) {
+                return Err(anyhow::Error::msg("lsb_depth must be between 8 and 24"));
+            }
+        }
+
+        if let Some(channels) = &self.force_channels {
+            if parse_channels(channels)? == Channels::Stereo && format.channels < 2 {
+                return Err(anyhow::Error::msg(format!("can not force stereo on {} channel", format.channels)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Overwrites the fields that are set in the other settings.
+    pub fn merge(&mut self, other: &EncoderSettings) {
+        if other.bit_rate.is_some() {
+            self.bit_rate = other.bit_rate;
+        }
+
+        if other.complexity.is_some() {
+            self.complexity = other.complexity;
+        }
+
+        if other.bandwidth.is_some() {
+            self.bandwidth = other.bandwidth.clone();
+        }
+
+        if other.signal.is_some() {
+            self.signal = other.signal.clone();
+        }
+
+        if other.lsb_depth.is_some() {
+            self.lsb_depth = other.lsb_depth;
+        }
+
+        if other.force_channels.is_some() {
+            self.force_channels = other.force_channels.clone();
+        }
+    }
+}
+
+pub fn parse_bandwidth(name: &str) -> anyhow::Result<Bandwidth> {
+    Ok(match name {
+        "auto" => Bandwidth::Auto,
+        "narrowband" => Bandwidth::Narrowband,
+        "mediumband" => Bandwidth::Mediumband,
+        "wideband" => Bandwidth::Wideband,
+        "superwideband" => Bandwidth::Superwideband,
+        "fullband" => Bandwidth::Fullband,
+        _ => return Err(anyhow::Error::msg(format!("unknown bandwidth: {}", name)))
+    })
+}
+
+pub fn bandwidth_name(bandwidth: Bandwidth) -> &'static str {
+    match bandwidth {
+        Bandwidth::Auto => "auto",
+        Bandwidth::Narrowband => "narrowband",
+        Bandwidth::Mediumband => "mediumband",
+        Bandwidth::Wideband => "wideband",
+        Bandwidth::Superwideband => "superwideband",
+        Bandwidth::Fullband => "fullband"
+    }
+}
+
+pub fn parse_signal(name: &str) -> anyhow::Result<Signal> {
+    Ok(match name {
+        "auto" => Signal::Auto,
+        "voice" => Signal::Voice,
+        "music" => Signal::Music,
+        _ => return Err(anyhow::Error::msg(format!("unknown signal: {}", name)))
+    })
+}
+
+pub fn signal_name(signal: Signal) -> &'static str {
+    match signal {
+        Signal::Auto => "auto",
+        Signal::Voice => "voice",
+        Signal::Music => "music"
+    }
+}
+
+pub fn parse_channels(name: &str) -> anyhow::Result<Channels> {
+    Ok(match name {
+        "auto" => Channels::Auto,
+        "mono" => Channels::Mono,
+        "stereo" => Channels::Stereo,
+        _ => return Err(anyhow::Error::msg(format!("unknown channels: {}", name)))
+    })
+}
+
+pub fn channels_name(channels: Channels) -> &'static str {
+    match channels {
+        Channels::Auto => "auto",
+        Channels::Mono => "mono",
+        Channels::Stereo => "stereo"
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Mp3Options {
+    /// Constant bitrate, in kbps
+    pub bit_rate: u32,
+    /// LAME algorithm quality, 0 (best) to 9 (fastest)
+    pub quality: u8,
+    pub max_page: Duration,
+    pub buffer_size: Duration
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct FlacOptions {
+    /// Samples per channel in a single frame
+    pub block_size: u16,
+    pub max_page: Duration,
+    pub buffer_size: Duration
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub enum Codec {
+    Opus(Options),
+    WebM(Options),
+    Fmp4(Options),
+    Packets(Options),
+    Mp3(Mp3Options),
+    Flac(FlacOptions)
+}
+
+impl Codec {
+
+    pub fn buffer_size(&self) -> Duration {
+        match self {
+            Codec::Opus(options) => options.buffer_size,
+            Codec::WebM(options) => options.buffer_size,
+            Codec::Fmp4(options) => options.buffer_size,
+            Codec::Packets(options) => options.buffer_size,
+            Codec::Mp3(options) => options.buffer_size,
+            Codec::Flac(options) => options.buffer_size
+        }
+    }
+
+    /// Nominal bitrate in kbps, if there is one.
+    pub fn bit_rate(&self) -> Option<u32> {
+        match self {
+            Codec::Opus(options)
+            | Codec::WebM(options)
+            | Codec::Fmp4(options)
+            | Codec::Packets(options) => match options.bit_rate {
+                Bitrate::BitsPerSecond(bits) => Some(bits as u32 / 1000),
+                _ => None
+            },
+
+            Codec::Mp3(options) => Some(options.bit_rate),
+            Codec::Flac(_) => None
+        }
+    }
+
+    /// Whether a restarted encoder can carry on the existing streams,
+    /// i.e. whether the format allows a new stream header in the middle (chained Ogg, MP3).
+    pub fn chainable(&self) -> bool {
+        matches!(self, Codec::Opus(_) | Codec::Mp3(_))
+    }
+
+    pub fn encoder(&self, format: AudioFormat) -> anyhow::Result<Box<dyn StreamEncoder>> {
+        Ok(match self {
+            Codec::Opus(options) => Box::new(Encoder::new(format, options)?),
+            Codec::WebM(options) => Box::new(WebmEncoder::new(format, options)?),
+            Codec::Fmp4(options) => Box::new(Fmp4Encoder::new(format, options)?),
+            Codec::Packets(options) => Box::new(PacketEncoder::new(format, options)?),
+            #[cfg(feature = "mp3")]
+            Codec::Mp3(options) => Box::new(Mp3Encoder::new(format, options)?),
+            #[cfg(not(feature = "mp3"))]
+            Codec::Mp3(_) => return Err(anyhow::Error::msg("built without MP3 support, enable the mp3 feature")),
+            Codec::Flac(options) => Box::new(FlacEncoder::new(format, options)?)
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) use tests::options as test_options;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Opus options with 20ms frames, as used by the default tiers.
+    pub fn options(max_page: Duration) -> Options {
+        Options {
+            frame_size: FrameSize::Ms20,
+            bit_rate: Bitrate::BitsPerSecond(64000),
+            signal: Signal::Music,
+            bandwidth: Bandwidth::Fullband,
+            application: Application::Audio,
+            max_page,
+            buffer_size: Duration::from_secs(7),
+            complexity: 0,
+            vbr: true,
+            vbr_constraint: false,
+            fec: false,
+            packet_loss: 0,
+            dtx: false,
+            lsb_depth: 24,
+            force_channels: Channels::Auto,
+            prediction_disabled: false,
+            output_gain: 0
+        }
+    }
+
+    #[test]
+    fn pager_cuts_once_full() {
+        let mut pager = Pager::new(48000, Duration::from_millis(100));
+        assert!(pager.is_empty());
+
+        pager.add(2880);
+        assert!(!pager.is_full());
+
+        pager.add(2880);
+        assert!(pager.is_full());
+
+        let page = pager.cut(Bytes::from_static(b"page"));
+        assert_eq!(page.data, Bytes::from_static(b"page"));
+        assert_eq!(page.duration, Duration::from_millis(120));
+        assert!(pager.is_empty());
+    }
+
+    #[test]
+    fn pager_duration_is_exact() {
+        // 1/48000 s is not a whole number of nanoseconds, the rounding must not accumulate
+        let mut pager = Pager::new(48000, Duration::ZERO);
+        pager.add(48000 * 60);
+
+        assert_eq!(pager.cut(Bytes::new()).duration, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn pager_without_max_page_is_always_full() {
+        let pager = Pager::new(44100, Duration::ZERO);
+        assert!(pager.is_full());
+    }
+}
@@ -0,0 +1,60 @@
+use super::{Mp3Options, Page, Pager, StreamEncoder, lame};
+use crate::AudioFormat;
+use bytes::{Bytes, BytesMut};
+use rocket::http::ContentType;
+
+/// Audio to MP3 (CBR) encoder
+pub struct Mp3Encoder {
+    lame: lame::LameEncoder,
+    header: Bytes,
+    buffer: BytesMut,
+    pager: Pager,
+}
+
+impl Mp3Encoder {
+
+    pub fn new(format: AudioFormat, options: &Mp3Options) -> anyhow::Result<Self> {
+        Ok(Self {
+            lame: lame::LameEncoder::new(format, options.bit_rate, options.quality)?,
+            header: Bytes::new(),
+            buffer: BytesMut::new(),
+            pager: Pager::new(format.sample_rate, options.max_page),
+        })
+    }
+}
+
+impl StreamEncoder for Mp3Encoder {
+    fn content_type(&self) -> ContentType {
+        ContentType::new("audio", "mpeg")
+    }
+
+    /// MP3 streams have no header, each frame is self-contained.
+    fn header(&self) -> &Bytes {
+        &self.header
+    }
+
+    fn push(&mut self, samples: &[f32]) -> anyhow::Result<Option<Page>> {
+        let channels = self.lame.format().channels as usize;
+
+        let frames = self.lame.encode(samples)?;
+        self.buffer.extend_from_slice(frames);
+        self.pager.add((samples.len() / channels) as u64);
+
+        if !self.pager.is_full() || self.buffer.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(self.pager.cut(self.buffer.split().freeze())))
+    }
+
+    fn finish(&mut self) -> anyhow::Result<Option<Page>> {
+        let frames = self.lame.flush()?;
+        self.buffer.extend_from_slice(frames);
+
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(self.pager.cut(self.buffer.split().freeze())))
+    }
+}
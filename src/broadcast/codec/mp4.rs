@@ -0,0 +1,349 @@
+use crate::AudioFormat;
+
+const TIMESCALE: u32 = 1000;
+const TRACK_ID: u32 = 1;
+const MATRIX: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
+
+fn write_box<F: FnOnce(&mut Vec<u8>)>(buffer: &mut Vec<u8>, kind: &[u8; 4], content: F) {
+    let start = buffer.len();
+    buffer.extend_from_slice(&[0; 4]);
+    buffer.extend_from_slice(kind);
+    content(buffer);
+
+    let size = (buffer.len() - start) as u32;
+    buffer[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box<F: FnOnce(&mut Vec<u8>)>(buffer: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, content: F) {
+    write_box(buffer, kind, |b| {
+        b.extend_from_slice(&((version as u32) << 24 | flags & 0xFFFFFF).to_be_bytes());
+        content(b);
+    })
+}
+
+fn write_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_cstr(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(0);
+}
+
+/// Fragmented MP4 muxer for a single Opus track.
+/// The init segment holds the track description, every taken fragment is a standalone media segment.
+pub struct Fmp4Stream {
+    format: AudioFormat,
+    sequence: u32,
+    decode_time: u64,
+    samples: u64,
+    entries: Vec<(u32, u32)>,
+    data: Vec<u8>,
+    events: Vec<u8>
+}
+
+impl Fmp4Stream {
+
+    pub fn new(format: AudioFormat) -> Self {
+        Self {
+            format,
+            sequence: 0,
+            decode_time: 0,
+            samples: 0,
+            entries: Vec::new(),
+            data: Vec::new(),
+            events: Vec::new()
+        }
+    }
+
+    /// Writes the init segment (`ftyp` + `moov`).
+    pub fn init(&self, pre_skip: u16, output_gain: i16) -> Vec<u8> {
+        let format = self.format;
+        let mut buffer = Vec::new();
+
+        write_box(&mut buffer, b"ftyp", |b| {
+            b.extend_from_slice(b"iso6");
+            write_u32(b, 0);
+            b.extend_from_slice(b"iso6mp41Opus");
+        });
+
+        write_box(&mut buffer, b"moov", |b| {
+            write_full_box(b, b"mvhd", 0, 0, |b| {
+                write_u32(b, 0);                    // creation time
+                write_u32(b, 0);                    // modification time
+                write_u32(b, TIMESCALE);
+                write_u32(b, 0);                    // duration (unknown)
+                write_u32(b, 0x00010000);           // rate
+                write_u16(b, 0x0100);               // volume
+                b.extend_from_slice(&[0; 10]);      // reserved
+                MATRIX.iter().for_each(|&m| write_u32(b, m));
+                b.extend_from_slice(&[0; 24]);      // pre defined
+                write_u32(b, TRACK_ID + 1);         // next track id
+            });
+
+            write_box(b, b"trak", |b| {
+                write_full_box(b, b"tkhd", 0, 0x3, |b| {
+                    write_u32(b, 0);                // creation time
+                    write_u32(b, 0);                // modification time
+                    write_u32(b, TRACK_ID);
+                    write_u32(b, 0);                // reserved
+                    write_u32(b, 0);                // duration (unknown)
+                    b.extend_from_slice(&[0; 8]);   // reserved
+                    write_u16(b, 0);                // layer
+                    write_u16(b, 0);                // alternate group
+                    write_u16(b, 0x0100);           // volume
+                    write_u16(b, 0);                // reserved
+                    MATRIX.iter().for_each(|&m| write_u32(b, m));
+                    write_u32(b, 0);                // width
+                    write_u32(b, 0);                // height
+                });
+
+                write_box(b, b"mdia", |b| {
+                    write_full_box(b, b"mdhd", 0, 0, |b| {
+                        write_u32(b, 0);            // creation time
+                        write_u32(b, 0);            // modification time
+                        write_u32(b, format.sample_rate);
+                        write_u32(b, 0);            // duration (unknown)
+                        write_u16(b, 0x55C4);       // language: und
+                        write_u16(b, 0);            // pre defined
+                    });
+
+                    write_full_box(b, b"hdlr", 0, 0, |b| {
+                        write_u32(b, 0);            // pre defined
+                        b.extend_from_slice(b"soun");
+                        b.extend_from_slice(&[0; 12]);
+                        write_cstr(b, "SoundHandler");
+                    });
+
+                    write_box(b, b"minf", |b| {
+                        write_full_box(b, b"smhd", 0, 0, |b| {
+                            write_u16(b, 0);        // balance
+                            write_u16(b, 0);        // reserved
+                        });
+
+                        write_box(b, b"dinf", |b| {
+                            write_full_box(b, b"dref", 0, 0, |b| {
+                                write_u32(b, 1);
+                                write_full_box(b, b"url ", 0, 0x1, |_| {});
+                            });
+                        });
+
+                        write_box(b, b"stbl", |b| {
+                            write_full_box(b, b"stsd", 0, 0, |b| {
+                                write_u32(b, 1);
+                                write_box(b, b"Opus", |b| {
+                                    b.extend_from_slice(&[0; 6]);       // reserved
+                                    write_u16(b, 1);                    // data reference index
+                                    b.extend_from_slice(&[0; 8]);       // reserved
+                                    write_u16(b, format.channels as u16);
+                                    write_u16(b, 16);                   // sample size
+                                    write_u16(b, 0);                    // pre defined
+                                    write_u16(b, 0);                    // reserved
+                                    write_u32(b, 48000 << 16);          // sample rate (fixed for opus)
+
+                                    write_box(b, b"dOps", |b| {
+                                        b.push(0);                      // version
+                                        b.push(format.channels);
+                                        write_u16(b, pre_skip);
+                                        write_u32(b, format.sample_rate);
+                                        b.extend_from_slice(&output_gain.to_be_bytes());
+                                        b.push(0);                      // channel mapping family
+                                    });
+                                });
+                            });
+
+                            write_full_box(b, b"stts", 0, 0, |b| write_u32(b, 0));
+                            write_full_box(b, b"stsc", 0, 0, |b| write_u32(b, 0));
+                            write_full_box(b, b"stsz", 0, 0, |b| {
+                                write_u32(b, 0);
+                                write_u32(b, 0);
+                            });
+                            write_full_box(b, b"stco", 0, 0, |b| write_u32(b, 0));
+                        });
+                    });
+                });
+            });
+
+            write_box(b, b"mvex", |b| {
+                write_full_box(b, b"trex", 0, 0, |b| {
+                    write_u32(b, TRACK_ID);
+                    write_u32(b, 1);                // default sample description index
+                    write_u32(b, 0);                // default sample duration
+                    write_u32(b, 0);                // default sample size
+                    write_u32(b, 0);                // default sample flags
+                });
+            });
+        });
+
+        buffer
+    }
+
+    /// Appends the packet to the current fragment.
+    pub fn put(&mut self, data: &[u8], samples: u64) {
+        self.entries.push((samples as u32, data.len() as u32));
+        self.data.extend_from_slice(data);
+        self.samples += samples;
+    }
+
+    /// Attaches a timed event (`emsg`) at the current position to the current fragment.
+    pub fn event(&mut self, scheme: &str, value: &str, message: &[u8]) {
+        let (sequence, samples) = (self.sequence, self.samples);
+        let sample_rate = self.format.sample_rate;
+
+        write_full_box(&mut self.events, b"emsg", 1, 0, |b| {
+            write_u32(b, sample_rate);
+            write_u64(b, samples);
+            write_u32(b, 0xFFFFFFFF);               // duration (unknown)
+            write_u32(b, sequence);                 // id
+            write_cstr(b, scheme);
+            write_cstr(b, value);
+            b.extend_from_slice(message);
+        });
+    }
+
+    /// Takes the current fragment as a media segment, if it is not empty.
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let mut buffer = Vec::new();
+
+        write_box(&mut buffer, b"styp", |b| {
+            b.extend_from_slice(b"msdh");
+            write_u32(b, 0);
+            b.extend_from_slice(b"msdhmsix");
+        });
+
+        buffer.append(&mut self.events);
+
+        let moof_start = buffer.len();
+        let mut data_offset = 0;
+
+        write_box(&mut buffer, b"moof", |b| {
+            write_full_box(b, b"mfhd", 0, 0, |b| write_u32(b, self.sequence));
+
+            write_box(b, b"traf", |b| {
+                write_full_box(b, b"tfhd", 0, 0x020000, |b| write_u32(b, TRACK_ID)); // default base is moof
+                write_full_box(b, b"tfdt", 1, 0, |b| write_u64(b, self.decode_time));
+
+                // data offset, sample duration & sample size present
+                write_full_box(b, b"trun", 0, 0x000301, |b| {
+                    write_u32(b, self.entries.len() as u32);
+                    data_offset = b.len();
+                    write_u32(b, 0);
+
+                    for &(duration, size) in self.entries.iter() {
+                        write_u32(b, duration);
+                        write_u32(b, size);
+                    }
+                });
+            });
+        });
+
+        // data starts right after the mdat header
+        let offset = (buffer.len() - moof_start + 8) as u32;
+        buffer[data_offset..data_offset + 4].copy_from_slice(&offset.to_be_bytes());
+
+        write_box(&mut buffer, b"mdat", |b| b.append(&mut self.data));
+
+        self.entries.clear();
+        self.decode_time = self.samples;
+
+        Some(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::io::Cursor;
+    use symphonia::core::codecs::CODEC_TYPE_OPUS;
+    use symphonia::core::formats::{FormatOptions, FormatReader};
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::default::formats::IsoMp4Reader;
+
+    const FORMAT: AudioFormat = AudioFormat {
+        channels: 2,
+        sample_rate: 48000
+    };
+
+    /// Top level boxes, as (type, size).
+    fn boxes(mut data: &[u8]) -> Vec<([u8; 4], usize)> {
+        let mut boxes = Vec::new();
+        while data.len() >= 8 {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            boxes.push((data[4..8].try_into().unwrap(), size));
+            data = &data[size..];
+        }
+
+        assert!(data.is_empty(), "trailing data");
+        boxes
+    }
+
+    #[test]
+    fn segments_are_self_contained() {
+        let mut stream = Fmp4Stream::new(FORMAT);
+        assert!(stream.take().is_none());
+
+        stream.event("urn:test", "", b"message");
+        stream.put(&[1; 20], 960);
+        stream.put(&[2; 30], 960);
+
+        let segment = stream.take().expect("no segment");
+        let kinds: Vec<_> = boxes(&segment).into_iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [*b"styp", *b"emsg", *b"moof", *b"mdat"]);
+
+        // the data offset of the run points right at the samples
+        let offset = segment.windows(4).position(|w| w == b"trun").unwrap() + 4 + 4 + 4;
+        let offset = u32::from_be_bytes(segment[offset..offset + 4].try_into().unwrap()) as usize;
+        let moof = segment.windows(4).position(|w| w == b"moof").unwrap() - 4;
+        assert_eq!(&segment[moof + offset..], [[1; 20].as_slice(), [2; 30].as_slice()].concat());
+    }
+
+    #[test]
+    fn stream_is_readable() {
+        let mut stream = Fmp4Stream::new(FORMAT);
+        let mut data = stream.init(312, 0);
+        assert_eq!(boxes(&data).into_iter().map(|(kind, _)| kind).collect::<Vec<_>>(), [*b"ftyp", *b"moov"]);
+
+        let packets: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i; 10 + i as usize]).collect();
+        for (i, packet) in packets.iter().enumerate() {
+            stream.put(packet, 960);
+
+            if i % 3 == 2 {
+                data.extend_from_slice(&stream.take().expect("no segment"));
+            }
+        }
+
+        let source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+        let mut reader = IsoMp4Reader::try_new(source, &FormatOptions::default()).expect("invalid init segment");
+
+        let params = &reader.tracks()[0].codec_params;
+        assert_eq!(params.codec, CODEC_TYPE_OPUS);
+        assert_eq!(params.sample_rate, Some(48000));
+
+        let mut read = Vec::new();
+        let mut timestamps = Vec::new();
+        while let Ok(packet) = reader.next_packet() {
+            assert_eq!(packet.dur, 960);
+            read.push(packet.data.to_vec());
+            timestamps.push(packet.ts);
+        }
+
+        // the reader counts the timestamps from the end of the first packet, only the spacing is checked
+        assert_eq!(read, packets);
+        assert!(timestamps.windows(2).all(|w| w[1] - w[0] == 960));
+    }
+}
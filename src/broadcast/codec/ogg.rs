@@ -11,8 +11,6 @@ use ogg_sys::{
     ogg_stream_pageout
 };
 
-const STREAM_ID: i32 = 888765668; //cool id
-
 pub struct OggStream {
     ogg: *mut ogg_stream_state,
     counter: i64,
@@ -42,10 +40,10 @@ fn write_page(page: &ogg_page, buffer: &mut Vec<u8>) -> bool {
 //literally c
 impl OggStream {
 
-    pub fn new() -> Self {
+    pub fn new(serial: i32) -> Self {
         unsafe {
             let ogg = Box::into_raw(Box::<ogg_stream_state>::new_zeroed().assume_init());
-            if ogg_stream_init(ogg, STREAM_ID) != 0 {
+            if ogg_stream_init(ogg, serial) != 0 {
                 panic!("ogg internal error")
             } //error if -1
 
@@ -59,6 +57,15 @@ impl OggStream {
     }
 
     pub fn put(&mut self, data: &[u8], samples: u64) {
+        self.put_packet(data, samples, false)
+    }
+
+    /// Puts the last packet of the logical stream.
+    pub fn finish(&mut self, data: &[u8], samples: u64) {
+        self.put_packet(data, samples, true)
+    }
+
+    fn put_packet(&mut self, data: &[u8], samples: u64, last: bool) {
         unsafe {
             self.samples = self.samples.wrapping_add(samples as i64);
 
@@ -66,7 +73,7 @@ impl OggStream {
                 packet: data.as_ptr() as *mut c_uchar,
                 bytes: data.len() as c_long,
                 b_o_s: if self.counter == 0 { 1 } else { 0 },
-                e_o_s: 0,
+                e_o_s: if last { 1 } else { 0 },
                 granulepos: self.samples,
                 packetno: self.counter
             };
@@ -133,4 +140,110 @@ impl<'a> Drop for OggSlice<'a> {
     fn drop(&mut self) {
         self.buffer.clear();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    pub const BOS: u8 = 0x02;
+    pub const EOS: u8 = 0x04;
+
+    /// Page of a physical stream, without its data.
+    #[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+    pub struct PageInfo {
+        pub flags: u8,
+        pub granule: i64,
+        pub serial: u32,
+        pub sequence: u32
+    }
+
+    fn crc(data: &[u8]) -> u32 {
+        data.iter().fold(0u32, |mut crc, &byte| {
+            crc ^= (byte as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x80000000 != 0 { (crc << 1) ^ 0x04C11DB7 } else { crc << 1 };
+            }
+
+            crc
+        })
+    }
+
+    /// Splits the stream into pages, checking their framing and checksums.
+    pub fn pages(mut data: &[u8]) -> Vec<PageInfo> {
+        let mut pages = Vec::new();
+
+        while !data.is_empty() {
+            assert_eq!(&data[..5], b"OggS\0", "not a page");
+
+            let segments = data[26] as usize;
+            let length = 27 + segments + data[27..27 + segments].iter().map(|&l| l as usize).sum::<usize>();
+
+            let mut page = data[..length].to_vec();
+            let checksum = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].fill(0);
+            assert_eq!(crc(&page), checksum, "invalid checksum");
+
+            pages.push(PageInfo {
+                flags: data[5],
+                granule: i64::from_le_bytes(data[6..14].try_into().unwrap()),
+                serial: u32::from_le_bytes(data[14..18].try_into().unwrap()),
+                sequence: u32::from_le_bytes(data[18..22].try_into().unwrap())
+            });
+
+            data = &data[length..];
+        }
+
+        pages
+    }
+
+    /// Reassembles the packets of a logical stream, in order.
+    pub fn packets(mut data: &[u8], serial: u32) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut packet = Vec::new();
+
+        while !data.is_empty() {
+            let segments = data[26] as usize;
+            let lacing = &data[27..27 + segments];
+            let mut body = &data[27 + segments..];
+            let length = 27 + segments + lacing.iter().map(|&l| l as usize).sum::<usize>();
+
+            if u32::from_le_bytes(data[14..18].try_into().unwrap()) == serial {
+                for &size in lacing {
+                    packet.extend_from_slice(&body[..size as usize]);
+                    body = &body[size as usize..];
+
+                    // a segment shorter than 255 bytes ends the packet
+                    if size < 255 {
+                        packets.push(std::mem::take(&mut packet));
+                    }
+                }
+            }
+
+            data = &data[length..];
+        }
+
+        packets
+    }
+
+    #[test]
+    fn logical_stream_is_framed() {
+        let mut ogg = OggStream::new(1234);
+        let mut data = Vec::new();
+
+        ogg.put(b"head", 0);
+        ogg.flush();
+        data.extend_from_slice(&ogg.take());
+
+        ogg.put(&[1; 100], 960);
+        ogg.finish(&[2; 100], 960);
+        ogg.flush();
+        data.extend_from_slice(&ogg.take());
+
+        let pages = pages(&data);
+        assert_eq!(pages.len(), 2);
+        assert_eq!((pages[0].flags, pages[0].serial, pages[0].sequence), (BOS, 1234, 0));
+        assert_eq!((pages[1].flags, pages[1].granule, pages[1].sequence), (EOS, 1920, 1));
+    }
+}
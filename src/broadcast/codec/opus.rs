@@ -1,30 +1,54 @@
 use audiopus;
+use audiopus::coder::GenericCtl;
 use std::io::{self, Write};
 use std::convert::TryFrom;
-use crate::{AudioSource, AudioFormat};
-use super::Options;
+use crate::{AudioFormat, Track};
+use super::{EncoderSettings, Options, Bitrate, parse_bandwidth, parse_channels, parse_signal};
 
 pub struct OpusEncoder {
     opus: audiopus::coder::Encoder,
     frame_buffer: Vec<f32>,
+    frame_filled: usize,
     byte_buffer: Vec<u8>,
-    format: AudioFormat
+    format: AudioFormat,
+    pre_skip: u16,
+    output_gain: i16,
+    settings: EncoderSettings
 }
 
 const BUFFER_SIZE: usize = 4000;
 
+/// Mapping family 0: mono or stereo, no mapping table.
+const CHANNEL_MAPPING_FAMILY: u8 = 0;
+
 impl OpusEncoder {
 
     pub fn new(format: AudioFormat, options: &Options) -> anyhow::Result<Self> {
+        if format.channels == 0 || format.channels > 2 {
+            return Err(anyhow::Error::msg(format!("opus: unsupported channel count {}", format.channels)));
+        }
+
         let sample_rate = audiopus::SampleRate::try_from(format.sample_rate as i32)?;
         let channels = audiopus::Channels::try_from(format.channels as i32)?;
 
         let mut opus = audiopus::coder::Encoder::new(sample_rate, channels, options.application)?;
 
+        opus.set_bitrate(options.bit_rate)?;
         opus.set_signal(options.signal)?;
         opus.set_bandwidth(options.bandwidth)?;
         opus.set_vbr(options.vbr)?;
         opus.set_complexity(options.complexity)?;
+        opus.set_vbr_constraint(options.vbr_constraint)?;
+        opus.set_inband_fec(options.fec)?;
+        opus.set_packet_loss_perc(options.packet_loss)?;
+        opus.set_dtx(options.dtx)?;
+        opus.set_lsb_depth(options.lsb_depth)?;
+        opus.set_force_channels(options.force_channels)?;
+        opus.set_prediction_disabled(options.prediction_disabled)?;
+
+        // pre-skip is always expressed at 48 kHz, regardless of the input rate
+        let pre_skip = opus.lookahead()? as u64 * 48000 / format.sample_rate as u64;
+        let pre_skip = u16::try_from(pre_skip)?;
 
         let frame_size = options.frame_size.as_sample_count(format.sample_rate) as usize * format.channels as usize;
 
@@ -32,7 +56,11 @@ impl OpusEncoder {
             opus,
             format,
             frame_buffer: vec![0.0; frame_size],
-            byte_buffer: vec![0u8; BUFFER_SIZE]
+            frame_filled: 0,
+            byte_buffer: vec![0u8; BUFFER_SIZE],
+            pre_skip,
+            output_gain: options.output_gain,
+            settings: EncoderSettings::from_options(options)
         })
     }
 
@@ -40,24 +68,95 @@ impl OpusEncoder {
         self.frame_buffer.len() as u64
     }
 
+    /// Samples per channel in a single packet.
+    pub fn packet_samples(&self) -> u64 {
+        self.frame_size() / self.format().channels as u64
+    }
+
     pub fn format(&self) -> AudioFormat {
         self.format
     }
 
+    /// Amount of samples (at 48 kHz) to discard at the start of the decoded stream.
+    pub fn pre_skip(&self) -> u16 {
+        self.pre_skip
+    }
+
+    /// Gain the decoder applies to the output, in Q7.8 dB.
+    pub fn output_gain(&self) -> i16 {
+        self.output_gain
+    }
+
+    /// Resets the encoder to its initial state, keeping the settings,
+    /// so that the packets from here on can be decoded without the ones before.
+    pub fn reset_state(&mut self) -> anyhow::Result<()> {
+        self.opus.reset_state()?;
+        Ok(())
+    }
+
+    /// Applies the settings to the encoder, taking effect from the next frame.
+    /// The stream headers are not affected, so the stream continues uninterrupted.
+    pub fn configure(&mut self, settings: &EncoderSettings) -> anyhow::Result<EncoderSettings> {
+        settings.validate(self.format)?;
+
+        if let Some(bit_rate) = settings.bit_rate {
+            self.opus.set_bitrate(Bitrate::BitsPerSecond(bit_rate))?;
+        }
+
+        if let Some(complexity) = settings.complexity {
+            self.opus.set_complexity(complexity)?;
+        }
+
+        if let Some(bandwidth) = &settings.bandwidth {
+            self.opus.set_bandwidth(parse_bandwidth(bandwidth)?)?;
+        }
+
+        if let Some(signal) = &settings.signal {
+            self.opus.set_signal(parse_signal(signal)?)?;
+        }
+
+        if let Some(lsb_depth) = settings.lsb_depth {
+            self.opus.set_lsb_depth(lsb_depth)?;
+        }
+
+        if let Some(channels) = &settings.force_channels {
+            self.opus.set_force_channels(parse_channels(channels)?)?;
+        }
+
+        self.settings.merge(settings);
+        Ok(self.settings.clone())
+    }
+
     pub fn write_header<W: Write>(&self, mut write: W) -> io::Result<()> {
         write.write(b"OpusHead")?;                              // magic
         write.write(&[1])?;                                     // opus version
         write.write(&[self.format.channels])?;                  // channels
-        write.write(&[0x38, 0x01])?;                            // pre skip
+        write.write(&self.pre_skip().to_le_bytes())?;           // pre skip
         write.write(&self.format.sample_rate.to_le_bytes())?;   // sample rate
-        write.write(&[0, 0, 0])?;                               // bruyh
+        write.write(&self.output_gain.to_le_bytes())?;          // output gain
+        write.write(&[CHANNEL_MAPPING_FAMILY])?;                // channel mapping family
 
         Ok(())
     }
 
-    pub fn write_tags<W: Write>(&self, mut write: W) -> io::Result<()> {
+    pub fn write_tags<W: Write>(&self, mut write: W, track: Option<&Track>) -> io::Result<()> {
         let vendor = format!("quartz {}", std::env!("CARGO_PKG_VERSION"));
-        let comments = [format!("encoder={} libopus", vendor)];
+        let mut comments = vec![format!("encoder={} libopus", vendor)];
+
+        if let Some(track) = track {
+            let fields = [
+                ("TITLE", &track.title),
+                ("ARTIST", &track.author),
+                ("VERSION", &track.subtitle),
+                ("LOCATION", &track.source_url)
+            ];
+
+            for (name, value) in fields {
+                if let Some(value) = value {
+                    comments.push(format!("{}={}", name, value));
+                }
+            }
+        }
 
         write.write(b"OpusTags")?;
         write.write(&(vendor.len() as u32).to_le_bytes())?;
@@ -73,19 +172,19 @@ impl OpusEncoder {
         Ok(())
     }
 
-    pub fn pull_page<S: AudioSource>(&mut self, source: &mut S) -> anyhow::Result<Option<&[u8]>> {
-        let mut written = 0;
-
-        while written < self.frame_buffer.len() {
-            let samples = source.pull(&mut self.frame_buffer[written..])?;
-            written += samples;
+    /// Feeds the samples into the frame buffer.
+    /// Returns the amount of samples consumed and the encoded packet if the frame got filled up.
+    pub fn push(&mut self, samples: &[f32]) -> anyhow::Result<(usize, Option<&[u8]>)> {
+        let consumed = samples.len().min(self.frame_buffer.len() - self.frame_filled);
+        self.frame_buffer[self.frame_filled..self.frame_filled + consumed].copy_from_slice(&samples[..consumed]);
+        self.frame_filled += consumed;
 
-            if samples == 0 { //eof reached
-                self.frame_buffer[written..].fill(0.0);
-                break;
-            }
+        if self.frame_filled < self.frame_buffer.len() {
+            return Ok((consumed, None));
         }
 
+        self.frame_filled = 0;
+
         // TODO fix
         // This is a hacky way to fix a bug where stream just stops loading on certain browsers (Firefox)
         // if all the samples in a page (?) are zero.
@@ -97,6 +196,6 @@ impl OpusEncoder {
         }
 
         let bytes = self.opus.encode_float(&self.frame_buffer, &mut self.byte_buffer)?;
-        Ok(Some(&self.byte_buffer[..bytes]))
+        Ok((consumed, Some(&self.byte_buffer[..bytes])))
     }
 }
\ No newline at end of file
@@ -0,0 +1,172 @@
+use super::{EncoderSettings, Options, Page, Pager, StreamEncoder, opus};
+use crate::{AudioFormat, Track};
+use bytes::{BufMut, Bytes, BytesMut};
+use rocket::http::ContentType;
+
+const FRAME_HEADER: u8 = 0;
+const FRAME_PACKET: u8 = 1;
+const FRAME_TRACK: u8 = 2;
+
+/// Raw Opus packet framer, meant for WebCodecs-style players.
+///
+/// Every page is a sequence of frames, each starting with a one byte type and
+/// a little-endian `u32` payload length:
+/// - `0`: header, payload is the `OpusHead` structure
+/// - `1`: packet, payload is the granule position (`u64`, at the end of the packet) followed by the packet itself
+/// - `2`: track change, payload is the track info as JSON
+pub struct PacketEncoder {
+    opus: opus::OpusEncoder,
+    header: Bytes,
+    buffer: BytesMut,
+    granule: u64,
+    pager: Pager,
+}
+
+fn put_frame(buffer: &mut BytesMut, kind: u8, length: usize) {
+    buffer.put_u8(kind);
+    buffer.put_u32_le(length as u32);
+}
+
+impl PacketEncoder {
+
+    pub fn new(format: AudioFormat, options: &Options) -> anyhow::Result<Self> {
+        let opus = opus::OpusEncoder::new(format, options)?;
+
+        let mut opus_head = Vec::new();
+        opus.write_header(&mut opus_head)?;
+
+        let mut header = BytesMut::new();
+        put_frame(&mut header, FRAME_HEADER, opus_head.len());
+        header.put_slice(&opus_head);
+
+        Ok(Self {
+            opus,
+            header: header.freeze(),
+            buffer: BytesMut::new(),
+            granule: 0,
+            pager: Pager::new(format.sample_rate, Default::default()),
+        })
+    }
+}
+
+impl StreamEncoder for PacketEncoder {
+    fn content_type(&self) -> ContentType {
+        ContentType::new("application", "octet-stream")
+    }
+
+    fn header(&self) -> &Bytes {
+        &self.header
+    }
+
+    fn push(&mut self, mut samples: &[f32]) -> anyhow::Result<Option<Page>> {
+        let spp = self.opus.packet_samples();
+
+        while !samples.is_empty() {
+            let (consumed, packet) = self.opus.push(samples)?;
+            samples = &samples[consumed..];
+
+            if let Some(packet) = packet {
+                self.granule += spp;
+                self.pager.add(spp);
+
+                put_frame(&mut self.buffer, FRAME_PACKET, packet.len() + 8);
+                self.buffer.put_u64_le(self.granule);
+                self.buffer.put_slice(packet);
+            }
+        }
+
+        // send the packets as soon as they are available
+        if self.pager.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(self.pager.cut(self.buffer.split().freeze())))
+    }
+
+    fn track(&mut self, track: &Track) {
+        if let Ok(json) = rocket::serde::json::serde_json::to_vec(track) {
+            put_frame(&mut self.buffer, FRAME_TRACK, json.len());
+            self.buffer.put_slice(&json);
+        }
+    }
+
+    fn configure(&mut self, settings: &EncoderSettings) -> anyhow::Result<EncoderSettings> {
+        self.opus.configure(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_options;
+    use std::convert::TryInto;
+    use std::time::Duration;
+
+    const FORMAT: AudioFormat = AudioFormat {
+        channels: 2,
+        sample_rate: 48000
+    };
+
+    /// Splits the stream into its frames.
+    fn frames(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let length = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize;
+            frames.push((data[0], &data[5..5 + length]));
+            data = &data[5 + length..];
+        }
+
+        frames
+    }
+
+    #[test]
+    fn frames_carry_their_type_and_length() {
+        let mut encoder = PacketEncoder::new(FORMAT, &test_options(Duration::from_millis(200))).unwrap();
+        let track = Track {
+            title: Some("Title".to_owned()),
+            subtitle: None,
+            author: None,
+            source_url: None,
+            background_url: None,
+            audio_url: "track.ogg".to_owned()
+        };
+
+        let mut data = encoder.header().to_vec();
+        for (i, milliseconds) in [100, 40].iter().enumerate() {
+            if i > 0 {
+                encoder.track(&track);
+            }
+
+            let page = encoder.push(&vec![0.0; 2 * 48 * milliseconds]).unwrap().expect("no packets");
+            data.extend_from_slice(&page.data);
+        }
+
+        let frames = frames(&data);
+        assert_eq!(frames.len(), 1 + 5 + 1 + 2);
+
+        let (kind, head) = frames[0];
+        assert_eq!(kind, FRAME_HEADER);
+        assert_eq!(head.len(), 19);
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[9], 2);
+
+        let (kind, json) = frames[6];
+        assert_eq!(kind, FRAME_TRACK);
+        assert_eq!(rocket::serde::json::serde_json::from_slice::<Track>(json).unwrap(), track);
+
+        // the granule positions go on across the track change
+        let packets: Vec<&[u8]> = frames.iter()
+            .filter(|(kind, _)| *kind != FRAME_TRACK)
+            .skip(1)
+            .map(|&(kind, payload)| {
+                assert_eq!(kind, FRAME_PACKET);
+                payload
+            })
+            .collect();
+
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() > 8);
+            assert_eq!(u64::from_le_bytes(packet[..8].try_into().unwrap()), (i as u64 + 1) * 960);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use crate::AudioFormat;
+
+// element ids, see https://www.matroska.org/technical/elements.html
+const EBML: u32 = 0x1A45DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549A966;
+const TIMECODE_SCALE: u32 = 0x2AD7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CODEC_DELAY: u32 = 0x56AA;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+
+const CLUSTER: u32 = 0x1F43B675;
+const TIMECODE: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+const TRACK: u8 = 1;
+
+fn write_id(buffer: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    buffer.extend_from_slice(&bytes[skip..]);
+}
+
+fn write_size(buffer: &mut Vec<u8>, size: u64) {
+    // all ones are reserved for the unknown size, so the value must be strictly less
+    let length = (1..8).find(|&l| size < (1u64 << (7 * l)) - 1).unwrap_or(8);
+    let marked = size | (1u64 << (7 * length));
+    buffer.extend_from_slice(&marked.to_be_bytes()[8 - length..]);
+}
+
+fn write_binary(buffer: &mut Vec<u8>, id: u32, data: &[u8]) {
+    write_id(buffer, id);
+    write_size(buffer, data.len() as u64);
+    buffer.extend_from_slice(data);
+}
+
+fn write_uint(buffer: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(7);
+    write_binary(buffer, id, &bytes[skip..]);
+}
+
+fn write_float(buffer: &mut Vec<u8>, id: u32, value: f64) {
+    write_binary(buffer, id, &value.to_be_bytes());
+}
+
+fn write_master<F: FnOnce(&mut Vec<u8>)>(buffer: &mut Vec<u8>, id: u32, children: F) {
+    let mut data = Vec::new();
+    children(&mut data);
+    write_binary(buffer, id, &data);
+}
+
+/// Live WebM muxer for a single Opus track.
+/// Every taken chunk is a self-contained cluster, so that listeners can join at any of them.
+pub struct WebmStream {
+    format: AudioFormat,
+    blocks: Vec<u8>,
+    cluster_start: u64,
+    samples: u64
+}
+
+impl WebmStream {
+
+    pub fn new(format: AudioFormat) -> Self {
+        Self {
+            format,
+            blocks: Vec::new(),
+            cluster_start: 0,
+            samples: 0
+        }
+    }
+
+    /// Writes the EBML header, the start of the (infinite) segment and the track info.
+    pub fn header(&self, opus_head: &[u8], pre_skip: u16) -> Vec<u8> {
+        let app = format!("quartz {}", std::env!("CARGO_PKG_VERSION"));
+        let mut buffer = Vec::new();
+
+        write_master(&mut buffer, EBML, |b| {
+            write_uint(b, EBML_VERSION, 1);
+            write_uint(b, EBML_READ_VERSION, 1);
+            write_uint(b, EBML_MAX_ID_LENGTH, 4);
+            write_uint(b, EBML_MAX_SIZE_LENGTH, 8);
+            write_binary(b, DOC_TYPE, b"webm");
+            write_uint(b, DOC_TYPE_VERSION, 4);
+            write_uint(b, DOC_TYPE_READ_VERSION, 2);
+        });
+
+        write_id(&mut buffer, SEGMENT);
+        buffer.extend_from_slice(&UNKNOWN_SIZE);
+
+        write_master(&mut buffer, INFO, |b| {
+            write_uint(b, TIMECODE_SCALE, 1_000_000); // timecodes are in ms
+            write_binary(b, MUXING_APP, app.as_bytes());
+            write_binary(b, WRITING_APP, app.as_bytes());
+        });
+
+        write_master(&mut buffer, TRACKS, |b| {
+            write_master(b, TRACK_ENTRY, |b| {
+                write_uint(b, TRACK_NUMBER, TRACK as u64);
+                write_uint(b, TRACK_UID, TRACK as u64);
+                write_uint(b, TRACK_TYPE, 2); // audio
+                write_binary(b, CODEC_ID, b"A_OPUS");
+                write_binary(b, CODEC_PRIVATE, opus_head);
+                write_uint(b, CODEC_DELAY, pre_skip as u64 * 1_000_000_000 / 48000);
+                write_uint(b, SEEK_PRE_ROLL, 80_000_000);
+                write_master(b, AUDIO, |b| {
+                    write_float(b, SAMPLING_FREQUENCY, self.format.sample_rate as f64);
+                    write_uint(b, CHANNELS, self.format.channels as u64);
+                });
+            });
+        });
+
+        buffer
+    }
+
+    fn timecode(&self, samples: u64) -> u64 {
+        samples * 1000 / self.format.sample_rate as u64
+    }
+
+    /// Appends the packet to the current cluster.
+    pub fn put(&mut self, data: &[u8], samples: u64) {
+        let relative = self.timecode(self.samples) - self.timecode(self.cluster_start);
+        self.samples += samples;
+
+        write_id(&mut self.blocks, SIMPLE_BLOCK);
+        write_size(&mut self.blocks, data.len() as u64 + 4);
+        self.blocks.push(0x80 | TRACK);                                 // track number
+        self.blocks.extend_from_slice(&(relative as i16).to_be_bytes()); // timecode relative to the cluster
+        self.blocks.push(0x80);                                         // flags: keyframe
+        self.blocks.extend_from_slice(data);
+    }
+
+    /// Takes the current cluster, if it is not empty.
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+
+        let mut buffer = Vec::new();
+        let timecode = self.timecode(self.cluster_start);
+
+        write_master(&mut buffer, CLUSTER, |b| {
+            write_uint(b, TIMECODE, timecode);
+            b.append(&mut self.blocks);
+        });
+
+        self.cluster_start = self.samples;
+        Some(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use symphonia::core::audio::Layout;
+    use symphonia::core::codecs::CODEC_TYPE_OPUS;
+    use symphonia::core::formats::{FormatOptions, FormatReader};
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::default::formats::MkvReader;
+
+    const FORMAT: AudioFormat = AudioFormat {
+        channels: 2,
+        sample_rate: 48000
+    };
+
+    #[test]
+    fn sizes_avoid_the_reserved_value() {
+        let size = |value| {
+            let mut buffer = Vec::new();
+            write_size(&mut buffer, value);
+            buffer
+        };
+
+        assert_eq!(size(0), [0x80]);
+        assert_eq!(size(126), [0xFE]);
+        // 0xFF would mean an unknown size
+        assert_eq!(size(127), [0x40, 0x7F]);
+        assert_eq!(size(0x3FFE), [0x7F, 0xFE]);
+        assert_eq!(size(0x3FFF), [0x20, 0x3F, 0xFF]);
+    }
+
+    #[test]
+    fn ids_keep_their_marker() {
+        let mut buffer = Vec::new();
+        write_id(&mut buffer, SIMPLE_BLOCK);
+        write_id(&mut buffer, CLUSTER);
+
+        assert_eq!(buffer, [0xA3, 0x1F, 0x43, 0xB6, 0x75]);
+    }
+
+    #[test]
+    fn stream_is_readable() {
+        let mut stream = WebmStream::new(FORMAT);
+        let mut data = stream.header(b"OpusHead\x01\x02\x38\x01\x80\xbb\0\0\0\0\0", 312);
+
+        let packets: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 10 + i as usize]).collect();
+        for (i, packet) in packets.iter().enumerate() {
+            stream.put(packet, 960);
+
+            // a listener can join at any of the clusters
+            if i == 2 || i == 4 {
+                data.extend_from_slice(&stream.take().expect("no cluster"));
+            }
+        }
+
+        assert!(stream.take().is_none());
+
+        let source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+        let mut reader = MkvReader::try_new(source, &FormatOptions::default()).expect("invalid header");
+
+        let params = &reader.tracks()[0].codec_params;
+        assert_eq!(params.codec, CODEC_TYPE_OPUS);
+        assert_eq!(params.sample_rate, Some(48000));
+        assert!(matches!(params.channel_layout, Some(Layout::Stereo)));
+
+        let mut read = Vec::new();
+        let mut timestamps = Vec::new();
+        while let Ok(packet) = reader.next_packet() {
+            read.push(packet.data.to_vec());
+            timestamps.push(packet.ts);
+        }
+
+        assert_eq!(read, packets);
+        assert!(timestamps.windows(2).all(|w| w[0] < w[1]));
+    }
+}
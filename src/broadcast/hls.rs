@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use parking_lot::RwLock;
+use rocket::{response, Request};
+use rocket::http::{ContentType, Header};
+
+use crate::{AudioFormat, Track};
+use crate::broadcast::codec::{Codec, StreamEncoder};
+use crate::broadcast::{Options, Sink};
+
+#[derive(Clone, Debug)]
+pub struct HlsOptions {
+    /// Encoder options; `max_page` is the segment length
+    pub codec: Options,
+    /// Amount of segments kept in the playlist
+    pub window: usize
+}
+
+struct Segment {
+    sequence: u64,
+    duration: Duration,
+    data: Bytes
+}
+
+struct State {
+    init: Bytes,
+    segments: VecDeque<Segment>,
+    next_sequence: u64
+}
+
+/// Cuts the broadcast into fMP4 segments and keeps a rolling window of them.
+pub struct Segmenter {
+    encoder: Box<dyn StreamEncoder>,
+    window: usize,
+    state: Arc<RwLock<State>>
+}
+
+impl Segmenter {
+
+    pub fn new(format: AudioFormat, options: &HlsOptions) -> anyhow::Result<(Self, Playlist)> {
+        let encoder = Codec::Fmp4(options.codec.clone()).encoder(format)?;
+        let state = Arc::new(RwLock::new(State {
+            init: encoder.header().clone(),
+            segments: VecDeque::new(),
+            next_sequence: 0
+        }));
+
+        let segmenter = Self {
+            encoder,
+            window: options.window.max(1),
+            state: state.clone()
+        };
+
+        Ok((segmenter, Playlist(state)))
+    }
+}
+
+impl Sink for Segmenter {
+    fn push(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        if let Some(page) = self.encoder.push(samples)? {
+            let mut state = self.state.write();
+            let sequence = state.next_sequence;
+
+            state.next_sequence += 1;
+            state.segments.push_back(Segment {
+                sequence,
+                duration: page.duration,
+                data: page.data
+            });
+
+            while state.segments.len() > self.window {
+                state.segments.pop_front();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn track(&mut self, track: &Track) {
+        self.encoder.track(track);
+    }
+}
+
+/// Live HLS playlist along with the segments it refers to.
+#[derive(Clone)]
+pub struct Playlist(Arc<RwLock<State>>);
+
+impl Playlist {
+
+    /// The token, if any, is appended to the URIs of the init segment and the media segments.
+    pub fn playlist(&self, token: Option<&str>) -> Resource {
+        let query = token
+            .map(|token| format!("?token={}", url::form_urlencoded::byte_serialize(token.as_bytes()).collect::<String>()))
+            .unwrap_or_default();
+
+        let state = self.0.read();
+        let target = state.segments.iter()
+            .map(|segment| segment.duration.as_secs_f64().ceil() as u64)
+            .max()
+            .unwrap_or(1);
+
+        let mut playlist = String::new();
+        let _ = writeln!(playlist, "#EXTM3U");
+        let _ = writeln!(playlist, "#EXT-X-VERSION:7");
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target);
+        let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", state.segments.front().map_or(0, |s| s.sequence));
+        let _ = writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS");
+        let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"init.mp4{}\"", query);
+
+        for segment in state.segments.iter() {
+            let _ = writeln!(playlist, "#EXTINF:{:.3},", segment.duration.as_secs_f64());
+            let _ = writeln!(playlist, "{}.m4s{}", segment.sequence, query);
+        }
+
+        Resource {
+            content_type: ContentType::new("application", "vnd.apple.mpegurl"),
+            data: Bytes::from(playlist),
+            cacheable: false
+        }
+    }
+
+    pub fn init(&self) -> Resource {
+        Resource {
+            content_type: ContentType::new("audio", "mp4"),
+            data: self.0.read().init.clone(),
+            cacheable: true
+        }
+    }
+
+    pub fn segment(&self, sequence: u64) -> Option<Resource> {
+        let state = self.0.read();
+        let segment = state.segments.iter().find(|segment| segment.sequence == sequence)?;
+
+        Some(Resource {
+            content_type: ContentType::new("audio", "mp4"),
+            data: segment.data.clone(),
+            cacheable: true
+        })
+    }
+}
+
+pub struct Resource {
+    content_type: ContentType,
+    data: Bytes,
+    cacheable: bool
+}
+
+impl<'r> response::Responder<'r, 'r> for Resource {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'r> {
+        let cache = if self.cacheable { "max-age=3600" } else { "no-cache, no-store" };
+
+        response::Response::build()
+            .header(self.content_type)
+            .header(Header::new("Access-Control-Allow-Origin", "*"))
+            .header(Header::new("Cache-Control", cache))
+            .sized_body(self.data.len(), Cursor::new(self.data))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broadcast::codec::test_options;
+
+    const FORMAT: AudioFormat = AudioFormat {
+        channels: 2,
+        sample_rate: 48000
+    };
+
+    fn options(window: usize) -> HlsOptions {
+        HlsOptions { codec: test_options(Duration::from_secs(1)), window }
+    }
+
+    fn text(resource: Resource) -> String {
+        String::from_utf8(resource.data.to_vec()).expect("not utf-8")
+    }
+
+    #[test]
+    fn playlist_keeps_a_window_of_segments() {
+        let (mut segmenter, playlist) = Segmenter::new(FORMAT, &options(2)).unwrap();
+        assert!(text(playlist.playlist(None)).lines().all(|line| line.starts_with('#')));
+
+        let second = vec![0.0; 2 * 48000];
+        for _ in 0..4 {
+            segmenter.push(&second).unwrap();
+        }
+
+        let text = text(playlist.playlist(None));
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert!(lines.contains(&"#EXT-X-MEDIA-SEQUENCE:2"));
+        assert!(lines.contains(&"#EXT-X-TARGETDURATION:1"));
+        assert!(lines.contains(&"#EXT-X-MAP:URI=\"init.mp4\""));
+        assert_eq!(lines.iter().filter(|line| !line.starts_with('#')).collect::<Vec<_>>(), [&"2.m4s", &"3.m4s"]);
+
+        assert!(playlist.segment(1).is_none());
+        assert!(playlist.segment(3).is_some());
+        assert_eq!(&playlist.init().data[4..8], b"ftyp");
+    }
+
+    #[test]
+    fn playlist_passes_the_token_on() {
+        let (mut segmenter, playlist) = Segmenter::new(FORMAT, &options(2)).unwrap();
+        segmenter.push(&vec![0.0; 2 * 48000]).unwrap();
+
+        let text = text(playlist.playlist(Some("a+b.c/d")));
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert!(lines.contains(&"#EXT-X-MAP:URI=\"init.mp4?token=a%2Bb.c%2Fd\""));
+        assert_eq!(lines.iter().filter(|line| !line.starts_with('#')).collect::<Vec<_>>(), [&"0.m4s?token=a%2Bb.c%2Fd"]);
+    }
+}
@@ -0,0 +1,263 @@
+use std::time::{Duration, Instant};
+use reqwest::{Body, Client, Url};
+use tokio::sync::oneshot;
+use crate::Track;
+use crate::broadcast::{Overflow, StreamManager};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct IcecastOptions {
+    /// Mount point url, e.g. `http://localhost:8000/quartz`
+    pub url: Url,
+    pub username: String,
+    pub password: String,
+    /// Tier whose stream is pushed to the server, the default one if not set
+    pub tier: Option<String>,
+    /// Whether the server should list the stream in the directories
+    pub public: bool
+}
+
+/// Pushes the stream to an Icecast server as a source client, reconnecting with backoff
+/// until the station shuts down.
+pub async fn relay(options: IcecastOptions, streams: StreamManager) {
+    let client = Client::new();
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let started = Instant::now();
+
+        match push(&client, &options, &streams).await {
+            Ok(()) => eprintln!("icecast: connection to {} closed", options.url),
+            Err(e) => eprintln!("icecast: failed to push to {}: {}", options.url, e)
+        }
+
+        // the connection was alive for long enough, so it is not a reconnect loop
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {},
+            _ = streams.closing() => break
+        }
+
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn push(client: &Client, options: &IcecastOptions, streams: &StreamManager) -> anyhow::Result<()> {
+    // the relay is not a listener, and carries on with the newest pages if the server is slow
+    let mut stream = streams.open_internal(options.tier.as_deref(), None, Overflow::DropOldest)?;
+
+    let info = streams.info();
+    let mut tracks = streams.tracks();
+    let content_type = stream.content_type().to_string();
+    let bit_rate = stream.bit_rate();
+
+    // the sender is dropped along with the body, i.e. when the connection is closed
+    let (closed, mut on_closed) = oneshot::channel::<()>();
+    let body = async_stream::stream! {
+        let _closed = closed;
+
+        while let Some(data) = stream.next().await {
+            yield Ok::<_, std::io::Error>(data);
+        }
+    };
+
+    let mut request = client.put(options.url.clone())
+        .basic_auth(&options.username, Some(&options.password))
+        .header("Content-Type", content_type)
+        .header("ice-name", &info.name)
+        .header("ice-genre", &info.genre)
+        .header("ice-description", &info.description)
+        .header("ice-url", &info.url)
+        .header("ice-public", if options.public { "1" } else { "0" });
+
+    if let Some(bit_rate) = bit_rate {
+        request = request.header("ice-bitrate", bit_rate.to_string());
+    }
+
+    let response = request
+        .body(Body::wrap_stream(body))
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow::Error::msg(format!("server responded with {}", response.status())));
+    }
+
+    if let Some(track) = tracks.current() {
+        update_metadata(client, options, &track).await;
+    }
+
+    loop {
+        tokio::select! {
+            _ = &mut on_closed => break,
+            Some(track) = tracks.poll() => update_metadata(client, options, &track).await
+        }
+    }
+
+    drop(response);
+    Ok(())
+}
+
+/// Updates the song title via the admin interface. Only matters for non-Ogg streams,
+/// since the server reads the metadata of Ogg streams from the stream itself.
+async fn update_metadata(client: &Client, options: &IcecastOptions, track: &Track) {
+    let song = match track.stream_title() {
+        Some(song) => song,
+        None => return
+    };
+
+    let mut url = options.url.clone();
+    url.set_path("/admin/metadata");
+    url.query_pairs_mut()
+        .clear()
+        .append_pair("mount", options.url.path())
+        .append_pair("mode", "updinfo")
+        .append_pair("song", &song);
+
+    let result = client.get(url)
+        .basic_auth(&options.username, Some(&options.password))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    if let Err(e) = result {
+        eprintln!("icecast: failed to update metadata: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::timeout;
+    use crate::broadcast::streamer::tests::silent_station;
+    use crate::events::EventStream;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn track() -> Track {
+        Track {
+            title: Some("Title".to_owned()),
+            subtitle: None,
+            author: Some("Author".to_owned()),
+            source_url: None,
+            background_url: None,
+            audio_url: "track.ogg".to_owned()
+        }
+    }
+
+    /// Accepts the next connection and reads its request line and headers (names in lowercase).
+    async fn accept(listener: &TcpListener) -> (BufReader<TcpStream>, String, HashMap<String, String>) {
+        let (socket, _) = timeout(TIMEOUT, listener.accept()).await.expect("no connection").unwrap();
+        let mut socket = BufReader::new(socket);
+
+        let mut request = String::new();
+        socket.read_line(&mut request).await.unwrap();
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            socket.read_line(&mut line).await.unwrap();
+
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => headers.insert(name.to_lowercase(), value.trim().to_owned()),
+                None => break
+            };
+        }
+
+        (socket, request.trim_end().to_owned(), headers)
+    }
+
+    #[tokio::test]
+    async fn relay_pushes_the_stream_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/quartz", listener.local_addr().unwrap());
+
+        let (tracks, mut handle) = EventStream::new();
+        handle.send(track());
+
+        // the handle is gone right away, so the track never changes
+        tokio::spawn(relay(IcecastOptions {
+            url: Url::parse(&url).unwrap(),
+            username: "source".to_owned(),
+            password: "hackme".to_owned(),
+            tier: Some("opus".to_owned()),
+            public: true
+        }, silent_station(tracks)));
+
+        let authorization = format!("Basic {}", base64::encode("source:hackme"));
+
+        // the source connection, answered the way Icecast does before the body ends
+        let (mut source, request, headers) = accept(&listener).await;
+        assert_eq!(request, "PUT /quartz HTTP/1.1");
+        assert_eq!(headers["authorization"], authorization);
+        assert_eq!(headers["content-type"], "audio/ogg");
+        assert_eq!(headers["ice-name"], "Quartz");
+        assert_eq!(headers["ice-genre"], "Ambient");
+        assert_eq!(headers["ice-description"], "Test station");
+        assert_eq!(headers["ice-url"], "https://example.com");
+        assert_eq!(headers["ice-public"], "1");
+        assert_eq!(headers["ice-bitrate"], "64");
+
+        source.get_mut().write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+
+        let mut body = Vec::new();
+        while !body.windows(4).any(|window| window == b"OggS") {
+            let mut chunk = [0u8; 4096];
+            let length = timeout(TIMEOUT, source.read(&mut chunk)).await.expect("no stream data").unwrap();
+            assert_ne!(length, 0, "source connection closed");
+            body.extend_from_slice(&chunk[..length]);
+        }
+
+        // then the title of the current track
+        let (mut admin, request, headers) = accept(&listener).await;
+        assert_eq!(request, "GET /admin/metadata?mount=%2Fquartz&mode=updinfo&song=Author+-+Title HTTP/1.1");
+        assert_eq!(headers["authorization"], authorization);
+        admin.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await.unwrap();
+        drop(admin);
+
+        // the server going away makes the relay connect again after the backoff
+        drop(source);
+
+        let (_source, request, headers) = accept(&listener).await;
+        assert_eq!(request, "PUT /quartz HTTP/1.1");
+        assert_eq!(headers["authorization"], authorization);
+    }
+
+    #[tokio::test]
+    async fn relay_is_not_a_listener_and_stops_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/quartz", listener.local_addr().unwrap());
+
+        let (tracks, _handle) = EventStream::new();
+        let streams = silent_station(tracks);
+        let relay = tokio::spawn(relay(IcecastOptions {
+            url: Url::parse(&url).unwrap(),
+            username: "source".to_owned(),
+            password: "hackme".to_owned(),
+            tier: None,
+            public: false
+        }, streams.clone()));
+
+        let (mut source, _, _) = accept(&listener).await;
+        source.get_mut().write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+
+        let mut chunk = [0u8; 4096];
+        let length = timeout(TIMEOUT, source.read(&mut chunk)).await.expect("no stream data").unwrap();
+        assert_ne!(length, 0, "source connection closed");
+
+        assert_eq!(streams.count(), 0);
+        assert!(streams.sessions().report().active.is_empty());
+
+        // the stream ends along with the broadcast, and there is no reconnecting afterwards
+        streams.shutdown(Duration::ZERO).await;
+        timeout(TIMEOUT, relay).await.expect("relay still running").unwrap();
+    }
+}
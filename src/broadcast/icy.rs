@@ -0,0 +1,137 @@
+use bytes::Bytes;
+use rocket::futures::Stream;
+use crate::Track;
+use crate::events::EventStream;
+use crate::broadcast::listener::Receiver;
+
+/// Amount of audio bytes between the metadata blocks.
+pub const METAINT: usize = 16000;
+
+const MAX_BLOCKS: usize = 255;
+const BLOCK_SIZE: usize = 16;
+
+/// Station info advertised to the SHOUTcast-style clients and directory services.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct StationInfo {
+    pub name: String,
+    pub genre: String,
+    pub description: String,
+    pub url: String
+}
+
+/// Interleaves the stream with `StreamTitle` metadata blocks every `METAINT` bytes.
+/// The title is only sent when it changes, otherwise the block is empty.
+pub fn interleave(mut receiver: Receiver, mut tracks: EventStream<Track>) -> impl Stream<Item = Bytes> {
+    async_stream::stream! {
+        let mut until_metadata = METAINT;
+        let mut last_title = None;
+
+        while let Some(mut data) = receiver.recv().await {
+            while !data.is_empty() {
+                if until_metadata == 0 {
+                    let title = tracks.current().and_then(|track| track.stream_title());
+                    if title != last_title {
+                        yield metadata(title.as_deref().unwrap_or(""));
+                        last_title = title;
+                    } else {
+                        yield Bytes::from_static(&[0]);
+                    }
+
+                    until_metadata = METAINT;
+                }
+
+                let chunk = data.split_to(until_metadata.min(data.len()));
+                until_metadata -= chunk.len();
+                yield chunk;
+            }
+        }
+    }
+}
+
+fn metadata(title: &str) -> Bytes {
+    // quotes would terminate the value early
+    let title = title.replace('\'', "\u{2019}");
+    let mut content = format!("StreamTitle='{}';", title).into_bytes();
+    content.truncate(MAX_BLOCKS * BLOCK_SIZE);
+
+    let blocks = content.len().div_ceil(BLOCK_SIZE);
+    content.resize(blocks * BLOCK_SIZE, 0);
+    content.insert(0, blocks as u8);
+
+    Bytes::from(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use rocket::futures::StreamExt;
+    use crate::broadcast::codec::Page;
+    use crate::broadcast::listener::Overflow;
+    use crate::broadcast::ring::{self, Item};
+
+    fn track(author: &str, title: &str) -> Track {
+        Track {
+            title: Some(title.to_owned()),
+            subtitle: None,
+            author: Some(author.to_owned()),
+            source_url: None,
+            background_url: None,
+            audio_url: "track.ogg".to_owned()
+        }
+    }
+
+    #[test]
+    fn metadata_is_padded_to_blocks() {
+        let block = metadata("Author - Title");
+        assert_eq!(block[0], 2);
+        assert_eq!(block.len(), 1 + 2 * BLOCK_SIZE);
+        assert_eq!(&block[1..30], b"StreamTitle='Author - Title';");
+        assert!(block[30..].iter().all(|&byte| byte == 0));
+
+        assert_eq!(&metadata("")[..], b"\x01StreamTitle='';\0");
+    }
+
+    #[test]
+    fn metadata_is_escaped_and_bounded() {
+        let block = metadata("Don't");
+        assert_eq!(&block[1..23], "StreamTitle='Don\u{2019}t';".as_bytes());
+
+        let block = metadata(&"a".repeat(10000));
+        assert_eq!(block[0] as usize, MAX_BLOCKS);
+        assert_eq!(block.len(), 1 + MAX_BLOCKS * BLOCK_SIZE);
+    }
+
+    #[tokio::test]
+    async fn metadata_is_sent_every_metaint_bytes() {
+        let (mut writer, ring) = ring::channel("icy", Duration::from_secs(10), None).unwrap();
+        let (tracks, mut handle) = EventStream::new();
+        handle.send(track("Author", "Title"));
+
+        let mut audio = Vec::new();
+        for i in 0..4 {
+            let data = Bytes::from(vec![i as u8; 10000]);
+            audio.extend_from_slice(&data);
+
+            writer.push(Item {
+                page: Page { data, duration: Duration::from_secs(1) },
+                header: Bytes::new(),
+                next_header: Bytes::new()
+            });
+        }
+
+        let receiver = Receiver::new(ring, Duration::from_secs(10), Overflow::Disconnect, Duration::from_secs(10), Duration::ZERO);
+        drop(writer);
+
+        let received = Box::pin(interleave(receiver, tracks)).collect::<Vec<Bytes>>().await.concat();
+
+        let mut expected = audio[..METAINT].to_vec();
+        expected.extend_from_slice(&metadata("Author - Title"));
+        expected.extend_from_slice(&audio[METAINT..2 * METAINT]);
+        // the title has not changed since
+        expected.push(0);
+        expected.extend_from_slice(&audio[2 * METAINT..]);
+
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,331 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::sync::watch;
+use crate::broadcast::ring::{Ring, Spill};
+
+/// What to do with a listener that has fallen behind by more than its maximum lag.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub enum Overflow {
+    /// Skip the oldest pages, resending the stream header if needed to keep it decodable
+    DropOldest,
+    /// Disconnect the listener
+    Disconnect
+}
+
+/// Listener's cursor into the shared ring buffer of a tier.
+pub struct Receiver {
+    ring: Arc<Ring>,
+    changes: watch::Receiver<u64>,
+    cursor: u64,
+    // stream generation the listener has started in
+    generation: u64,
+    burst: Duration,
+    // pages are only sent once they are this far behind the head
+    delay: Duration,
+    // header the listener has received last, compared by identity
+    header: Option<Bytes>,
+    lagging: bool,
+    max_lag: Duration,
+    overflow: Overflow,
+    // whether the receiver shows up in the listener statistics of the ring
+    counted: bool
+}
+
+enum Next {
+    Data(Bytes),
+    Spilled(Spill),
+    Pending,
+    Closed
+}
+
+impl Receiver {
+
+    /// Starts reading at the oldest page that is at most `offset + burst` behind the head,
+    /// then keeps the listener `offset` behind (or less, if the history is shorter).
+    pub fn new(ring: Arc<Ring>, max_lag: Duration, overflow: Overflow, burst: Duration, offset: Duration) -> Self {
+        Self::open(ring, max_lag, overflow, burst, offset, true)
+    }
+
+    /// Same as `new`, but left out of the listener statistics, for the consumers within the station.
+    pub fn internal(ring: Arc<Ring>, max_lag: Duration, overflow: Overflow, burst: Duration, offset: Duration) -> Self {
+        Self::open(ring, max_lag, overflow, burst, offset, false)
+    }
+
+    fn open(ring: Arc<Ring>, max_lag: Duration, overflow: Overflow, burst: Duration, offset: Duration, counted: bool) -> Self {
+        if counted {
+            ring.stats.listeners.fetch_add(1, Relaxed);
+        }
+
+        let changes = ring.subscribe();
+        let (cursor, generation, burst, delay) = {
+            let state = ring.read();
+            let cursor = state.seek(offset + burst);
+            let lag = state.lag(cursor);
+            let delay = offset.min(lag);
+
+            (cursor, state.generation(), lag - delay, delay)
+        };
+
+        Self {
+            ring, changes, cursor, generation, burst, delay,
+            header: None,
+            lagging: false,
+            max_lag,
+            overflow,
+            counted
+        }
+    }
+
+    /// Actual burst, always a whole number of pages.
+    pub fn burst(&self) -> Duration {
+        self.burst
+    }
+
+    /// Actual timeshift offset.
+    pub fn offset(&self) -> Duration {
+        self.delay
+    }
+
+    /// Stream time at the end of the last page received.
+    pub fn position(&self) -> Duration {
+        self.ring.read().position(self.cursor)
+    }
+
+    /// Makes the next chunk the stream header, e.g. to start a new file that is decodable on its own.
+    pub fn resync(&mut self) {
+        self.header = None;
+    }
+
+    /// Receives the next chunk of the stream, preceded by the stream header whenever
+    /// the listener does not have the one the chunk requires.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        loop {
+            // mark the changes as seen before looking, so that none are missed
+            drop(self.changes.borrow_and_update());
+
+            match self.try_recv() {
+                Next::Data(data) => return Some(self.sent(data)),
+                Next::Spilled(spill) => return match tokio::task::spawn_blocking(move || spill.read()).await {
+                    Ok(Ok(data)) => Some(self.sent(data)),
+                    Ok(Err(e)) => {
+                        eprintln!("timeshift: failed to read a page: {}", e);
+                        None
+                    },
+
+                    Err(_) => None
+                },
+
+                Next::Pending => {},
+                Next::Closed => return None
+            }
+
+            self.changes.changed().await.ok()?;
+        }
+    }
+
+    fn try_recv(&mut self) -> Next {
+        let ring = self.ring.clone();
+        let state = ring.read();
+
+        // the stream has started over and can not be continued
+        if state.generation() != self.generation {
+            return Next::Closed;
+        }
+
+        // the timeshift delay does not count as falling behind
+        if state.lag(self.cursor).saturating_sub(self.delay) > self.max_lag {
+            match self.overflow {
+                Overflow::Disconnect => {
+                    if self.counted {
+                        ring.stats.overflowed.fetch_add(1, Relaxed);
+                    }
+
+                    return Next::Closed;
+                },
+
+                Overflow::DropOldest => {
+                    let cursor = state.seek(self.max_lag + self.delay);
+                    if self.counted {
+                        ring.stats.dropped.fetch_add(cursor.saturating_sub(self.cursor), Relaxed);
+                    }
+
+                    self.cursor = cursor;
+                }
+            }
+        }
+
+        let lag = state.lag(self.cursor);
+        self.set_lagging(lag.saturating_sub(self.delay) > self.max_lag / 2);
+
+        // timeshifted listeners play at real time
+        if lag < self.delay {
+            return Next::Pending;
+        }
+
+        let (item, spill) = match state.get(self.cursor) {
+            Some(page) => page,
+            None => return Next::Pending
+        };
+
+        let synced = self.header.as_ref().is_some_and(|header| same(header, &item.header));
+        if !synced {
+            self.header = Some(item.header.clone());
+
+            if !item.header.is_empty() {
+                return Next::Data(item.header.clone());
+            }
+        }
+
+        self.cursor += 1;
+        self.header = Some(item.next_header.clone());
+        if self.counted {
+            ring.stats.pages_sent.fetch_add(1, Relaxed);
+        }
+
+        match spill {
+            Some(spill) => Next::Spilled(spill.clone()),
+            None => Next::Data(item.page.data.clone())
+        }
+    }
+
+    fn sent(&self, data: Bytes) -> Bytes {
+        if self.counted {
+            self.ring.stats.bytes_sent.fetch_add(data.len() as u64, Relaxed);
+        }
+
+        data
+    }
+
+    fn set_lagging(&mut self, lagging: bool) {
+        if lagging != self.lagging && self.counted {
+            if lagging {
+                self.ring.stats.lagging.fetch_add(1, Relaxed);
+            } else {
+                self.ring.stats.lagging.fetch_sub(1, Relaxed);
+            }
+
+            self.lagging = lagging;
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.set_lagging(false);
+        if self.counted {
+            self.ring.stats.listeners.fetch_sub(1, Relaxed);
+        }
+    }
+}
+
+fn same(a: &Bytes, b: &Bytes) -> bool {
+    a.as_ptr() == b.as_ptr() && a.len() == b.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broadcast::codec::Page;
+    use crate::broadcast::ring::{self, Item, Writer};
+
+    fn ring() -> (Writer, Arc<Ring>) {
+        ring::channel("test", Duration::from_secs(10), None).unwrap()
+    }
+
+    fn push(writer: &mut Writer, pages: std::ops::Range<u8>) {
+        for index in pages {
+            writer.push(Item {
+                page: Page {
+                    data: Bytes::from(vec![index; 100]),
+                    duration: Duration::from_secs(1)
+                },
+                header: Bytes::new(),
+                next_header: Bytes::new()
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn overrun_listener_skips_to_the_max_lag() {
+        let (mut writer, ring) = ring();
+        let mut receiver = Receiver::new(ring.clone(), Duration::from_secs(2), Overflow::DropOldest, Duration::ZERO, Duration::ZERO);
+        push(&mut writer, 0..5);
+
+        // the pages more than 2s behind are skipped, the rest is still over half the max lag
+        assert_eq!(receiver.recv().await.unwrap(), vec![3; 100]);
+        assert_eq!(ring.stats.dropped.load(Relaxed), 3);
+        assert_eq!(ring.stats.lagging.load(Relaxed), 1);
+
+        assert_eq!(receiver.recv().await.unwrap(), vec![4; 100]);
+        assert_eq!(ring.stats.lagging.load(Relaxed), 0);
+        assert_eq!(ring.stats.pages_sent.load(Relaxed), 2);
+        assert_eq!(ring.stats.bytes_sent.load(Relaxed), 200);
+        assert_eq!(ring.stats.overflowed.load(Relaxed), 0);
+
+        assert_eq!(ring.stats.listeners.load(Relaxed), 1);
+        drop(receiver);
+        assert_eq!(ring.stats.listeners.load(Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn overrun_listener_is_disconnected() {
+        let (mut writer, ring) = ring();
+        let mut receiver = Receiver::new(ring.clone(), Duration::from_secs(2), Overflow::Disconnect, Duration::ZERO, Duration::ZERO);
+        push(&mut writer, 0..5);
+
+        assert_eq!(receiver.recv().await, None);
+        assert_eq!(ring.stats.overflowed.load(Relaxed), 1);
+        assert_eq!(ring.stats.dropped.load(Relaxed), 0);
+        assert_eq!(ring.stats.pages_sent.load(Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn timeshift_is_read_back_from_the_spill() {
+        let timeshift = ring::Timeshift {
+            length: Duration::from_secs(10),
+            memory: Duration::from_secs(2),
+            directory: std::env::temp_dir().join(format!("quartz-listener-{}", rand::random::<u64>()))
+        };
+
+        let (mut writer, ring) = ring::channel("test", Duration::from_secs(1), Some(&timeshift)).unwrap();
+        push(&mut writer, 0..8);
+
+        let started = std::time::Instant::now();
+        while ring.read().get(3).unwrap().1.is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5), "pages not spilled");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut receiver = Receiver::new(ring.clone(), Duration::from_secs(2), Overflow::Disconnect, Duration::ZERO, Duration::from_secs(5));
+        assert_eq!(receiver.offset(), Duration::from_secs(5));
+        assert_eq!(receiver.recv().await.unwrap(), vec![3; 100]);
+
+        // the next page is only due once the head has moved on
+        assert!(tokio::time::timeout(Duration::from_millis(50), receiver.recv()).await.is_err());
+        push(&mut writer, 8..9);
+        assert_eq!(receiver.recv().await.unwrap(), vec![4; 100]);
+        assert_eq!(ring.stats.bytes_sent.load(Relaxed), 200);
+
+        // no further back than the history goes
+        let oldest = Receiver::new(ring.clone(), Duration::from_secs(2), Overflow::Disconnect, Duration::ZERO, Duration::from_secs(1000));
+        assert_eq!(oldest.offset(), Duration::from_secs(9));
+
+        drop((receiver, oldest, writer, ring));
+        std::fs::remove_dir_all(&timeshift.directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn internal_receiver_is_left_out_of_the_stats() {
+        let (mut writer, ring) = ring();
+        let mut receiver = Receiver::internal(ring.clone(), Duration::from_secs(2), Overflow::DropOldest, Duration::ZERO, Duration::ZERO);
+        push(&mut writer, 0..5);
+
+        assert_eq!(receiver.recv().await.unwrap(), vec![3; 100]);
+        assert_eq!(ring.stats.listeners.load(Relaxed), 0);
+        assert_eq!(ring.stats.lagging.load(Relaxed), 0);
+        assert_eq!(ring.stats.dropped.load(Relaxed), 0);
+        assert_eq!(ring.stats.pages_sent.load(Relaxed), 0);
+    }
+}
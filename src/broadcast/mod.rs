@@ -1,13 +1,36 @@
 mod pump;
 mod codec;
 mod streamer;
+mod hls;
+mod icy;
+mod listener;
+mod ring;
+mod icecast;
+mod recorder;
+mod sessions;
 
 pub use streamer::*;
+pub use hls::*;
+pub use icy::StationInfo;
+pub use icecast::*;
+pub use recorder::*;
+pub use sessions::*;
+pub use listener::Overflow;
+pub use ring::Timeshift;
+pub use pump::{Pump, Clock, SystemClock, ManualClock, PumpStats};
 pub use codec::{
+    Codec,
     Options,
+    EncoderSettings,
+    Mp3Options,
+    FlacOptions,
     Application,
     Signal,
     Bandwidth,
     Bitrate,
-    FrameSize
+    Channels,
+    FrameSize,
+    parse_bandwidth,
+    parse_channels,
+    parse_signal
 };
\ No newline at end of file
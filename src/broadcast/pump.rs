@@ -1,50 +1,188 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
 use std::time::{Instant, Duration};
-use bytes::Bytes;
+use parking_lot::Mutex;
 use crate::{AudioFormat, AudioSource};
-use crate::broadcast::codec::Page;
-use super::codec::{Options, Encoder};
 
-/// Audio pump. Used for getting pages from an encoder in a timely manner.
-pub struct Pump {
-    encoder: Encoder,
+/// Time source pacing the pump.
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+
+    /// Blocks until the deadline, or returns right away if it has passed.
+    fn sleep_until(&self, deadline: Instant);
+}
+
+/// Real time. Relies on the OS timer alone, since oversleeping only delays a block
+/// that the next one makes up for, and the listeners are buffered way beyond the timer resolution.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        if let Some(duration) = deadline.checked_duration_since(Instant::now()) {
+            std::thread::sleep(duration);
+        }
+    }
+}
+
+/// Simulated time which only moves when slept on or advanced, so that the pump
+/// runs as fast as the source can be pulled, deterministically.
+#[derive(Clone, Debug)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+impl ManualClock {
+
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Moves the time forward, e.g. to simulate a stall.
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock() += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let mut now = self.0.lock();
+        *now = (*now).max(deadline);
+    }
+}
+
+/// Pacing statistics of a pump, in nanoseconds.
+#[derive(Default, Debug)]
+pub struct PumpStats {
+    /// How late the last block was pulled
+    pub drift: AtomicU64,
+    /// Total time skipped after falling behind by more than the buffer
+    pub skipped: AtomicU64,
+    /// Times the pump has skipped ahead
+    pub corrections: AtomicU64
+}
+
+/// Audio pump. Used for pulling fixed-size sample blocks from a source in a timely manner.
+pub struct Pump<C: Clock = SystemClock> {
+    block: Vec<f32>,
+    block_duration: Duration,
     next_pull: Instant,
-    buffer_size: Duration
+    buffer_size: Duration,
+    clock: C,
+    stats: Arc<PumpStats>
 }
 
 impl Pump {
 
-    pub fn new(format: AudioFormat, options: &Options) -> anyhow::Result<Self> {
-        let encoder = Encoder::new(format, options)?;
+    pub fn new(format: AudioFormat, block_duration: Duration, buffer_size: Duration) -> Self {
+        Self::with_clock(format, block_duration, buffer_size, SystemClock)
+    }
+}
+
+impl<C: Clock> Pump<C> {
 
-        Ok(Self {
-            encoder,
-            next_pull: Instant::now(),
-            buffer_size: options.buffer_size
-        })
+    pub fn with_clock(format: AudioFormat, block_duration: Duration, buffer_size: Duration, clock: C) -> Self {
+        let frames = block_duration.as_nanos() as u64 * format.sample_rate as u64 / 1_000_000_000u64;
+
+        Self {
+            block: vec![0.0; frames as usize * format.channels as usize],
+            block_duration,
+            next_pull: clock.now(),
+            buffer_size,
+            clock,
+            stats: Arc::new(PumpStats::default())
+        }
     }
 
-    pub fn header(&self) -> &Bytes {
-        self.encoder.header()
+    pub fn stats(&self) -> Arc<PumpStats> {
+        self.stats.clone()
     }
 
-    pub fn run<S: AudioSource>(&mut self, source: S) -> anyhow::Result<Option<Page>> {
-        if let Some(lag) = Instant::now()
-            .checked_duration_since(self.next_pull)
-            .and_then(|f| f.checked_sub(self.buffer_size))
-        {
+    pub fn run<S: AudioSource>(&mut self, mut source: S) -> anyhow::Result<&[f32]> {
+        let now = self.clock.now();
+        let late = now.saturating_duration_since(self.next_pull);
+        self.stats.drift.store(late.as_nanos() as u64, Relaxed);
+
+        // the listeners can not be behind by more than the buffer, so the rest is lost anyway
+        if let Some(lag) = late.checked_sub(self.buffer_size) {
             self.next_pull += lag;
-        }
+            self.stats.skipped.fetch_add(lag.as_nanos() as u64, Relaxed);
+            self.stats.corrections.fetch_add(1, Relaxed);
 
-        if let Some(sleep) = self.next_pull.checked_duration_since(Instant::now()) {
-            spin_sleep::sleep(sleep);
+            eprintln!("audio pump fell behind, skipping {:?}", lag);
         }
 
-        match self.encoder.pull(source)? {
-            None => Ok(None),
-            Some(page) => {
-                self.next_pull += page.duration;
-                Ok(Some(page))
+        self.clock.sleep_until(self.next_pull);
+        self.next_pull += self.block_duration;
+
+        let mut written = 0;
+        while written < self.block.len() {
+            let samples = source.pull(&mut self.block[written..])?;
+            written += samples;
+
+            if samples == 0 { //eof reached
+                self.block[written..].fill(0.0);
+                break;
             }
         }
+
+        Ok(&self.block)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broadcast::streamer::tests::{Silence, FORMAT};
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn stats(pump: &Pump<ManualClock>) -> (Duration, Duration, u64) {
+        let stats = pump.stats();
+        let nanos = |value: &AtomicU64| Duration::from_nanos(value.load(Relaxed));
+
+        (nanos(&stats.drift), nanos(&stats.skipped), stats.corrections.load(Relaxed))
+    }
+
+    #[test]
+    fn pump_reports_drift_and_skips_ahead() {
+        let clock = ManualClock::new();
+        let started = clock.now();
+        let mut pump = Pump::with_clock(FORMAT, 20 * MS, 1000 * MS, clock.clone());
+
+        assert_eq!(pump.run(Silence).unwrap().len(), 2 * 960);
+        assert_eq!(stats(&pump), (Duration::ZERO, Duration::ZERO, 0));
+
+        // on time, the clock only moves by sleeping
+        pump.run(Silence).unwrap();
+        assert_eq!(clock.now() - started, 20 * MS);
+        assert_eq!(stats(&pump), (Duration::ZERO, Duration::ZERO, 0));
+
+        // late, but within the buffer
+        clock.advance(500 * MS);
+        pump.run(Silence).unwrap();
+        assert_eq!(stats(&pump), (480 * MS, Duration::ZERO, 0));
+
+        // beyond the buffer, the rest is skipped
+        clock.advance(3000 * MS);
+        pump.run(Silence).unwrap();
+        assert_eq!(stats(&pump), (3460 * MS, 2460 * MS, 1));
+
+        pump.run(Silence).unwrap();
+        assert_eq!(stats(&pump), (980 * MS, 2460 * MS, 1));
+        assert_eq!(clock.now() - started, 3520 * MS);
+    }
+}
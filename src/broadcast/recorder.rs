@@ -0,0 +1,354 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rocket::http::ContentType;
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+use crate::Track;
+use crate::events::EventStream;
+use crate::broadcast::{OpenError, Overflow, Stream, StreamManager};
+
+/// When the recording moves on to a new file.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub enum Rotation {
+    /// At every multiple of the interval since the epoch, e.g. every full hour
+    Every(Duration),
+    /// At every track (or show) change
+    Track
+}
+
+#[derive(Clone, Debug)]
+pub struct RecorderOptions {
+    pub directory: PathBuf,
+    /// File name prefix, files are named `<prefix>-<start time>.<extension>`
+    pub prefix: String,
+    /// Tier whose stream is recorded
+    pub tier: String,
+    pub rotation: Rotation,
+    /// Files older than this are deleted
+    pub retention: Duration
+}
+
+/// Extensions of the recordings, by the content types of the tiers.
+const EXTENSIONS: [(&str, &str); 6] = [
+    ("ogg", "ogg"),
+    ("webm", "webm"),
+    ("mp4", "mp4"),
+    ("mpeg", "mp3"),
+    ("flac", "flac"),
+    ("octet-stream", "bin")
+];
+
+/// Line of the sidecar track log.
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    /// Seconds since the start of the file
+    offset: f64,
+    track: &'a Track
+}
+
+struct Recording {
+    audio: File,
+    log: File,
+    // stream time at the start of the file
+    start: Duration
+}
+
+impl Recording {
+
+    async fn close(mut self) -> io::Result<()> {
+        self.audio.flush().await?;
+        self.log.flush().await
+    }
+}
+
+/// Writes the stream of a tier to rotating archive files, along with a log of the tracks played,
+/// until the broadcast ends. The pages are read from the tier like a listener does,
+/// so the broadcast thread never waits on the disk.
+pub async fn record(options: RecorderOptions, streams: StreamManager) {
+    if let Err(e) = tokio::fs::create_dir_all(&options.directory).await {
+        eprintln!("recorder: failed to create {}: {}", options.directory.display(), e);
+        return;
+    }
+
+    let mut recorder = Recorder {
+        tracks: streams.tracks(),
+        options,
+        rotate_at: None,
+        rotate: false
+    };
+
+    loop {
+        // the stream ends whenever the tier starts over, e.g. after an encoder restart, or the recorder
+        // falls behind, so that a gap in the recording starts a new file rather than going unnoticed
+        let stream = match streams.open_internal(Some(&recorder.options.tier), Some(Duration::ZERO), Overflow::Disconnect) {
+            Ok(stream) => stream,
+            Err(OpenError::Closed) => break,
+            Err(e) => {
+                eprintln!("recorder: {}", e);
+                break;
+            }
+        };
+
+        recorder.record(stream).await;
+    }
+}
+
+struct Recorder {
+    options: RecorderOptions,
+    tracks: EventStream<Track>,
+    rotate_at: Option<SystemTime>,
+    rotate: bool
+}
+
+impl Recorder {
+
+    async fn record(&mut self, mut stream: Stream) {
+        let extension = extension(stream.content_type());
+        let mut recording = None;
+
+        loop {
+            tokio::select! {
+                data = stream.next() => {
+                    let data = match data {
+                        Some(data) => data,
+                        None => break
+                    };
+
+                    // a fresh (or resynced) stream starts with the header, so every file is decodable on its own
+                    let mut current = match recording.take() {
+                        Some(current) => current,
+                        None => match self.open(extension, stream.position()).await {
+                            Ok(current) => current,
+                            Err(e) => {
+                                eprintln!("recorder: failed to start a file: {}", e);
+                                stream.resync();
+                                continue;
+                            }
+                        }
+                    };
+
+                    // on failure, the next chunk starts a new file
+                    if let Err(e) = current.audio.write_all(&data).await {
+                        eprintln!("recorder: failed to write: {}", e);
+                        stream.resync();
+                        continue;
+                    }
+
+                    let due = self.rotate || self.rotate_at.is_some_and(|at| SystemTime::now() >= at);
+                    if due {
+                        close(current).await;
+                        stream.resync();
+                    } else {
+                        recording = Some(current);
+                    }
+                },
+
+                Some(track) = self.tracks.poll() => {
+                    if self.options.rotation == Rotation::Track {
+                        self.rotate = true;
+                    } else if let Some(current) = &mut recording {
+                        // as of the last page written, i.e. accurate to a page
+                        let offset = stream.position().saturating_sub(current.start);
+                        if let Err(e) = log_track(&mut current.log, offset, &track).await {
+                            eprintln!("recorder: failed to log the track: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(current) = recording {
+            close(current).await;
+        }
+    }
+
+    /// Starts a new file at the stream time `start`.
+    async fn open(&mut self, extension: &str, start: Duration) -> anyhow::Result<Recording> {
+        let now = SystemTime::now();
+        let name = format!("{}-{}", self.options.prefix, timestamp(now));
+
+        let audio = File::create(self.options.directory.join(format!("{}.{}", name, extension))).await?;
+        let log = File::create(self.options.directory.join(format!("{}.tracks", name))).await?;
+
+        self.rotate = false;
+        self.rotate_at = match self.options.rotation {
+            Rotation::Every(interval) => Some(next_boundary(now, interval)),
+            Rotation::Track => None
+        };
+
+        let mut recording = Recording { audio, log, start };
+        if let Some(track) = self.tracks.current() {
+            log_track(&mut recording.log, Duration::ZERO, &track).await?;
+        }
+
+        let (directory, prefix, retention) = (self.options.directory.clone(), self.options.prefix.clone(), self.options.retention);
+        tokio::task::spawn_blocking(move || remove_expired(&directory, &prefix, retention, now));
+
+        Ok(recording)
+    }
+}
+
+async fn close(recording: Recording) {
+    if let Err(e) = recording.close().await {
+        eprintln!("recorder: failed to write: {}", e);
+    }
+}
+
+fn extension(content_type: &ContentType) -> &'static str {
+    EXTENSIONS.iter()
+        .find(|(subtype, _)| content_type.sub() == *subtype)
+        .map_or("bin", |(_, extension)| extension)
+}
+
+/// Deletes the recordings (and their logs) that are past the retention period.
+fn remove_expired(directory: &Path, prefix: &str, retention: Duration, now: SystemTime) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("recorder: failed to list {}: {}", directory.display(), e);
+            return;
+        }
+    };
+
+    let prefix = format!("{}-", prefix);
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        let recording = name.rsplit_once('.').is_some_and(|(_, extension)| {
+            extension == "tracks" || EXTENSIONS.iter().any(|(_, known)| extension == *known)
+        });
+
+        if !name.starts_with(&prefix) || !recording {
+            continue;
+        }
+
+        let expired = entry.metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() > retention);
+
+        if expired {
+            if let Err(e) = fs::remove_file(entry.path()) {
+                eprintln!("recorder: failed to remove {}: {}", name, e);
+            }
+        }
+    }
+}
+
+async fn log_track(log: &mut File, offset: Duration, track: &Track) -> anyhow::Result<()> {
+    let entry = LogEntry { offset: offset.as_secs_f64(), track };
+    let mut line = rocket::serde::json::serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+
+    log.write_all(&line).await?;
+    Ok(())
+}
+
+fn next_boundary(now: SystemTime, interval: Duration) -> SystemTime {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let interval = interval.as_secs().max(1);
+
+    UNIX_EPOCH + Duration::from_secs((since_epoch / interval + 1) * interval)
+}
+
+/// UTC time formatted as `YYYY-MM-DDTHH-MM-SSZ`, safe for file names.
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, seconds) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02}T{:02}-{:02}-{:02}Z", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{sleep, timeout};
+    use crate::broadcast::codec::ogg_tests::{self, BOS};
+    use crate::broadcast::streamer::tests::silent_station;
+
+    fn track(title: &str) -> Track {
+        Track {
+            title: Some(title.to_owned()),
+            subtitle: None,
+            author: None,
+            source_url: None,
+            background_url: None,
+            audio_url: "track.ogg".to_owned()
+        }
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00-00-00Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951782400 + 3661)), "2000-02-29T01-01-01Z");
+
+        let now = UNIX_EPOCH + Duration::from_secs(3600 * 5 + 10);
+        assert_eq!(next_boundary(now, Duration::from_secs(3600)), UNIX_EPOCH + Duration::from_secs(3600 * 6));
+    }
+
+    #[tokio::test]
+    async fn every_track_gets_a_decodable_file() {
+        let directory = std::env::temp_dir().join(format!("quartz-recorder-{}", rand::random::<u64>()));
+        let (tracks, mut handle) = EventStream::new();
+        handle.send(track("First"));
+
+        let streams = silent_station(tracks);
+        let recorder = tokio::spawn(record(RecorderOptions {
+            directory: directory.clone(),
+            prefix: "test".to_owned(),
+            tier: "opus".to_owned(),
+            rotation: Rotation::Track,
+            retention: Duration::from_secs(60)
+        }, streams.clone()));
+
+        // the files are named by the second they start at
+        sleep(Duration::from_millis(1100)).await;
+        handle.send(track("Second"));
+        sleep(Duration::from_millis(1100)).await;
+
+        // the recorder is not a listener
+        assert_eq!(streams.count(), 0);
+        assert!(streams.sessions().report().active.is_empty());
+
+        streams.shutdown(Duration::ZERO).await;
+        timeout(Duration::from_secs(5), recorder).await.expect("recorder still running").unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(&directory).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+
+        let recordings: Vec<&String> = names.iter().filter(|name| name.ends_with(".ogg")).collect();
+        assert_eq!(recordings.len(), 2);
+        assert_eq!(names.len(), 4);
+
+        for (recording, title) in recordings.into_iter().zip(["First", "Second"]) {
+            let pages = ogg_tests::pages(&fs::read(directory.join(recording)).unwrap());
+            assert_eq!(pages[0].flags, BOS);
+            assert!(pages.len() > 2);
+
+            let log = fs::read_to_string(directory.join(recording.replace(".ogg", ".tracks"))).unwrap();
+            let first = log.lines().next().unwrap();
+            assert!(first.starts_with("{\"offset\":0.0,"), "{}", first);
+            assert!(first.contains(title), "{}", first);
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
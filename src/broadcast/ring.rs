@@ -0,0 +1,430 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+use std::time::Duration;
+use bytes::Bytes;
+use parking_lot::{RwLock, RwLockReadGuard};
+use tokio::sync::watch;
+use crate::broadcast::codec::Page;
+
+/// Size of a history file after which a new one is started.
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Long page history for the listeners that start in the past.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Timeshift {
+    /// How far in the past listeners can start
+    pub length: Duration,
+    /// Pages older than this are only kept on disk
+    pub memory: Duration,
+    /// Directory for the spilled pages
+    pub directory: PathBuf
+}
+
+/// Page along with the stream headers before and after it.
+#[derive(Clone, Debug)]
+pub struct Item {
+    pub page: Page,
+    /// Header required to decode the stream starting from this page
+    pub header: Bytes,
+    /// Header in effect after this page (differs if the page starts a new chained stream)
+    pub next_header: Bytes
+}
+
+struct Entry {
+    item: Item,
+    // stream time at the start of the page
+    position: Duration,
+    // where the page data is on disk, if it has been spilled (the data in memory is dropped then)
+    spill: Option<Spill>
+}
+
+/// Pages currently held by the ring, addressed by sequence numbers.
+pub struct State {
+    entries: VecDeque<Entry>,
+    // sequence number of the oldest entry
+    first: u64,
+    // sequence number of the oldest entry that has not been handed to the spill thread yet
+    resident: u64,
+    // stream time at the end of the newest entry
+    head: Duration,
+    // incremented whenever the stream starts over
+    generation: u64
+}
+
+impl State {
+
+    /// Sequence number the next page is going to get.
+    pub fn next(&self) -> u64 {
+        self.first + self.entries.len() as u64
+    }
+
+    /// Returns the page, along with the location of its data if it is only on disk
+    /// (the data of such page is empty).
+    pub fn get(&self, seq: u64) -> Option<(&Item, Option<&Spill>)> {
+        let index = seq.checked_sub(self.first)?;
+        let entry = self.entries.get(index as usize)?;
+
+        Some((&entry.item, entry.spill.as_ref()))
+    }
+
+    /// How far behind the head the page is, `Duration::MAX` if it has already been evicted.
+    pub fn lag(&self, seq: u64) -> Duration {
+        if seq < self.first {
+            return Duration::MAX;
+        }
+
+        match self.entries.get((seq - self.first) as usize) {
+            Some(entry) => self.head - entry.position,
+            None => Duration::ZERO
+        }
+    }
+
+    /// Stream time at the start of the page, or at the head if the page is yet to come.
+    pub fn position(&self, seq: u64) -> Duration {
+        self.head.saturating_sub(self.lag(seq))
+    }
+
+    /// Incremented whenever the stream starts over, i.e. the pages before can not be followed by the ones after.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Sequence number of the oldest page that is at most `lag` behind the head.
+    pub fn seek(&self, lag: Duration) -> u64 {
+        self.first + self.entries.partition_point(|entry| self.head - entry.position > lag) as u64
+    }
+}
+
+/// History file, removed once no page refers to it anymore.
+pub struct Segment {
+    file: File,
+    path: PathBuf
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Location of a spilled page.
+#[derive(Clone)]
+pub struct Spill {
+    segment: Arc<Segment>,
+    offset: u64,
+    length: usize
+}
+
+impl Spill {
+
+    /// Reads the page data, blocking.
+    pub fn read(&self) -> io::Result<Bytes> {
+        let mut data = vec![0u8; self.length];
+        self.segment.file.read_exact_at(&mut data, self.offset)?;
+        Ok(Bytes::from(data))
+    }
+}
+
+/// Listener counters of a ring, updated by the listeners themselves.
+#[derive(Default)]
+pub struct Stats {
+    pub listeners: AtomicUsize,
+    /// Listeners that are over half of their maximum lag behind
+    pub lagging: AtomicUsize,
+    /// Pages skipped by the listeners that fell behind
+    pub dropped: AtomicU64,
+    /// Listeners disconnected for falling behind
+    pub overflowed: AtomicU64,
+    /// Pages published by the writer
+    pub pages: AtomicU64,
+    /// Pages sent to the listeners
+    pub pages_sent: AtomicU64,
+    /// Bytes sent to the listeners, headers included
+    pub bytes_sent: AtomicU64
+}
+
+/// Page buffer shared by all the listeners of a tier.
+pub struct Ring {
+    state: RwLock<State>,
+    changes: watch::Receiver<u64>,
+    pub stats: Stats
+}
+
+impl Ring {
+
+    pub fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read()
+    }
+
+    /// Notifications about new pages, which end once the writer is gone.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.clone()
+    }
+}
+
+/// Creates a ring holding `capacity` worth of pages (or more, if the timeshift needs it), along with its only writer.
+pub fn channel(name: &str, capacity: Duration, timeshift: Option<&Timeshift>) -> io::Result<(Writer, Arc<Ring>)> {
+    let (changes, receiver) = watch::channel(0);
+    let ring = Arc::new(Ring {
+        state: RwLock::new(State {
+            entries: VecDeque::new(),
+            first: 0,
+            resident: 0,
+            head: Duration::ZERO,
+            generation: 0
+        }),
+        changes: receiver,
+        stats: Stats::default()
+    });
+
+    let spills = match timeshift {
+        Some(timeshift) => {
+            fs::create_dir_all(&timeshift.directory)?;
+
+            let history = History {
+                directory: timeshift.directory.clone(),
+                name: name.to_string(),
+                segment: None,
+                count: 0
+            };
+
+            // the disk writes are kept off the broadcast thread
+            let (spills, evictions) = mpsc::channel();
+            let ring = ring.clone();
+            thread::spawn(move || spill(ring, history, evictions));
+
+            Some(spills)
+        },
+
+        None => None
+    };
+
+    let writer = Writer {
+        ring: ring.clone(),
+        changes,
+        capacity: timeshift.map_or(capacity, |timeshift| capacity + timeshift.length),
+        memory: timeshift.map_or(capacity, |timeshift| capacity.max(timeshift.memory)),
+        spills
+    };
+
+    Ok((writer, ring))
+}
+
+struct History {
+    directory: PathBuf,
+    name: String,
+    // current file and its size
+    segment: Option<(Arc<Segment>, u64)>,
+    count: u64
+}
+
+impl History {
+
+    fn append(&mut self, data: &[u8]) -> io::Result<Spill> {
+        let (segment, size) = match self.segment.take() {
+            Some((segment, size)) if size < SEGMENT_SIZE => (segment, size),
+            _ => {
+                let path = self.directory.join(format!("{}-{}.pages", self.name, self.count));
+                self.count += 1;
+
+                let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+                (Arc::new(Segment { file, path }), 0)
+            }
+        };
+
+        segment.file.write_all_at(data, size)?;
+
+        let spill = Spill {
+            segment: segment.clone(),
+            offset: size,
+            length: data.len()
+        };
+
+        self.segment = Some((segment, size + data.len() as u64));
+        Ok(spill)
+    }
+}
+
+/// Page that has gone past the memory length of the ring.
+struct Eviction {
+    seq: u64,
+    data: Bytes
+}
+
+/// Writes the evicted pages to the history and drops their data from memory,
+/// until the writer is gone. Pages that fail to be written stay in memory instead.
+fn spill(ring: Arc<Ring>, mut history: History, evictions: mpsc::Receiver<Eviction>) {
+    for eviction in evictions {
+        let spill = match history.append(&eviction.data) {
+            Ok(spill) => spill,
+            Err(e) => {
+                eprintln!("timeshift: failed to spill a page: {}", e);
+                continue;
+            }
+        };
+
+        let mut state = ring.state.write();
+
+        // the page might be gone by now, if the ring has been reset
+        let entry = eviction.seq.checked_sub(state.first)
+            .and_then(|index| state.entries.get_mut(index as usize));
+
+        if let Some(entry) = entry {
+            entry.item.page.data = Bytes::new();
+            entry.spill = Some(spill);
+        }
+    }
+}
+
+/// Appends the pages, owned by the broadcast thread.
+pub struct Writer {
+    ring: Arc<Ring>,
+    changes: watch::Sender<u64>,
+    capacity: Duration,
+    memory: Duration,
+    // evicted pages for the spill thread, if there is a timeshift
+    spills: Option<mpsc::Sender<Eviction>>
+}
+
+impl Writer {
+
+    pub fn push(&mut self, item: Item) {
+        let next = {
+            let mut state = self.ring.state.write();
+            let position = state.head;
+
+            state.head += item.page.duration;
+            state.entries.push_back(Entry { item, position, spill: None });
+
+            while let Some(entry) = state.entries.front() {
+                if state.head - entry.position <= self.capacity {
+                    break;
+                }
+
+                state.entries.pop_front();
+                state.first += 1;
+            }
+
+            // keep only the recent pages in memory, the older ones are moved to disk
+            state.resident = state.resident.max(state.first);
+            while state.resident < state.next() {
+                let index = (state.resident - state.first) as usize;
+                let entry = &state.entries[index];
+
+                if state.head - entry.position <= self.memory {
+                    break;
+                }
+
+                if let Some(spills) = &self.spills {
+                    let _ = spills.send(Eviction {
+                        seq: state.resident,
+                        data: entry.item.page.data.clone()
+                    });
+                }
+
+                state.resident += 1;
+            }
+
+            state.next()
+        };
+
+        self.ring.stats.pages.fetch_add(1, Relaxed);
+        let _ = self.changes.send(next);
+    }
+
+    /// Drops all the pages and starts a new generation, ending the streams of the current listeners.
+    pub fn reset(&mut self) {
+        let next = {
+            let mut state = self.ring.state.write();
+
+            state.first = state.next();
+            state.resident = state.first;
+            state.entries.clear();
+            state.generation += 1;
+
+            state.next()
+        };
+
+        let _ = self.changes.send(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn item(index: u8) -> Item {
+        Item {
+            page: Page {
+                data: Bytes::from(vec![index; 100]),
+                duration: Duration::from_secs(1)
+            },
+            header: Bytes::new(),
+            next_header: Bytes::new()
+        }
+    }
+
+    #[test]
+    fn evicted_pages_are_spilled_to_disk() {
+        let timeshift = Timeshift {
+            length: Duration::from_secs(10),
+            memory: Duration::from_secs(2),
+            directory: std::env::temp_dir().join(format!("quartz-ring-{}", rand::random::<u64>()))
+        };
+
+        let (mut writer, ring) = channel("test", Duration::from_secs(1), Some(&timeshift)).unwrap();
+        for index in 0..5 {
+            writer.push(item(index));
+        }
+
+        // the two most recent pages are within the memory length
+        let started = Instant::now();
+        while (0..3).any(|seq| ring.read().get(seq).unwrap().1.is_none()) {
+            assert!(started.elapsed() < Duration::from_secs(5), "pages not spilled");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let state = ring.read();
+        for seq in 0..3 {
+            let (item, spill) = state.get(seq).unwrap();
+            assert!(item.page.data.is_empty());
+            assert_eq!(spill.unwrap().read().unwrap(), vec![seq as u8; 100]);
+        }
+
+        for seq in 3..5 {
+            let (item, spill) = state.get(seq).unwrap();
+            assert!(spill.is_none());
+            assert_eq!(item.page.data, vec![seq as u8; 100]);
+        }
+
+        drop(state);
+        drop((writer, ring));
+        fs::remove_dir_all(&timeshift.directory).unwrap();
+    }
+
+    #[test]
+    fn reset_starts_a_new_generation() {
+        let (mut writer, ring) = channel("test", Duration::from_secs(2), None).unwrap();
+        for index in 0..4 {
+            writer.push(item(index));
+        }
+
+        // only the capacity is kept
+        assert_eq!(ring.read().seek(Duration::MAX), 2);
+        assert_eq!(ring.read().lag(1), Duration::MAX);
+
+        writer.reset();
+        let state = ring.read();
+        assert_eq!(state.generation(), 1);
+        assert_eq!(state.next(), 4);
+        assert!(state.get(3).is_none());
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::{Serialize, Deserialize};
+
+/// Number of finished sessions kept for the report.
+const RECENT_SESSIONS: usize = 100;
+
+/// Listening session, either ongoing or finished.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct SessionRecord {
+    pub id: u64,
+    pub tier: String,
+    /// Unix time, in seconds
+    pub connected: u64,
+    /// In seconds
+    pub duration: u64,
+    pub bytes: u64,
+    pub user_agent: Option<String>,
+    pub address: Option<IpAddr>
+}
+
+/// Aggregate statistics over all the sessions so far.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct Statistics {
+    /// Most listeners connected at once
+    pub peak: usize,
+    /// Unix time of the peak, in seconds
+    pub peak_at: u64,
+    /// Finished sessions
+    pub sessions: u64,
+    /// Total listening time of the finished sessions
+    pub listening_hours: f64,
+    /// In seconds, over the finished sessions
+    pub average_session: f64,
+    /// Total bytes sent by the finished sessions
+    pub bytes: u64
+}
+
+/// Snapshot of the sessions, also the format of the persisted state.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct SessionReport {
+    pub statistics: Statistics,
+    #[serde(default)]
+    pub active: Vec<SessionRecord>,
+    #[serde(default)]
+    pub recent: Vec<SessionRecord>
+}
+
+impl SessionReport {
+
+    /// Leaves out the sessions, which identify the listeners.
+    pub fn aggregate(self) -> Self {
+        Self {
+            statistics: self.statistics,
+            active: Vec::new(),
+            recent: Vec::new()
+        }
+    }
+}
+
+struct Live {
+    id: u64,
+    tier: String,
+    connected: SystemTime,
+    started: Instant,
+    bytes: AtomicU64,
+    client: Mutex<(Option<IpAddr>, Option<String>)>
+}
+
+impl Live {
+
+    fn record(&self) -> SessionRecord {
+        let (address, user_agent) = self.client.lock().clone();
+
+        SessionRecord {
+            id: self.id,
+            tier: self.tier.clone(),
+            connected: unix(self.connected),
+            duration: self.started.elapsed().as_secs(),
+            bytes: self.bytes.load(Relaxed),
+            user_agent,
+            address
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next: u64,
+    live: BTreeMap<u64, Arc<Live>>,
+    recent: VecDeque<SessionRecord>,
+    statistics: Statistics,
+    // exact total, the hours are derived from it
+    listened: Duration
+}
+
+/// Listener sessions of a station.
+#[derive(Clone, Default)]
+pub struct Sessions(Arc<Mutex<State>>);
+
+impl Sessions {
+
+    /// Starts a session, which ends once the returned handle is dropped.
+    pub fn start(&self, tier: &str) -> Session {
+        let mut state = self.0.lock();
+        let id = state.next;
+        state.next += 1;
+
+        let live = Arc::new(Live {
+            id,
+            tier: tier.to_string(),
+            connected: SystemTime::now(),
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            client: Mutex::new((None, None))
+        });
+
+        state.live.insert(id, live.clone());
+        if state.live.len() > state.statistics.peak {
+            state.statistics.peak = state.live.len();
+            state.statistics.peak_at = unix(live.connected);
+        }
+
+        Session {
+            live,
+            sessions: self.clone()
+        }
+    }
+
+    pub fn report(&self) -> SessionReport {
+        let state = self.0.lock();
+
+        SessionReport {
+            statistics: state.statistics.clone(),
+            active: state.live.values().map(|live| live.record()).collect(),
+            recent: state.recent.iter().cloned().collect()
+        }
+    }
+
+    /// Restores the statistics saved with `save`, if there are any.
+    pub fn load(&self, path: &Path) -> anyhow::Result<()> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into())
+        };
+
+        let report: SessionReport = rocket::serde::json::serde_json::from_slice(&data)?;
+        let mut state = self.0.lock();
+
+        state.listened = Duration::from_secs_f64(report.statistics.listening_hours * 3600.0);
+        state.statistics = report.statistics;
+        state.recent = report.recent.into();
+        state.next = state.recent.iter().map(|record| record.id + 1).max().unwrap_or(0);
+
+        Ok(())
+    }
+
+    /// Saves the statistics and the recent sessions, the active ones are saved as well but not restored.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = rocket::serde::json::serde_json::to_vec(&self.report())?;
+
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+
+        // written aside first, so that a crash does not leave a partial file behind
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(temporary, path)?;
+
+        Ok(())
+    }
+
+    fn finish(&self, live: &Live) {
+        let record = live.record();
+        let mut state = self.0.lock();
+
+        state.live.remove(&live.id);
+        state.listened += live.started.elapsed();
+
+        let listened = state.listened.as_secs_f64();
+        let statistics = &mut state.statistics;
+        statistics.sessions += 1;
+        statistics.bytes += record.bytes;
+        statistics.listening_hours = listened / 3600.0;
+        statistics.average_session = listened / statistics.sessions as f64;
+
+        if state.recent.len() == RECENT_SESSIONS {
+            state.recent.pop_front();
+        }
+
+        state.recent.push_back(record);
+    }
+}
+
+/// Handle of an ongoing session.
+pub struct Session {
+    live: Arc<Live>,
+    sessions: Sessions
+}
+
+impl Session {
+
+    pub fn sent(&self, bytes: usize) {
+        self.live.bytes.fetch_add(bytes as u64, Relaxed);
+    }
+
+    pub fn identify(&self, address: Option<IpAddr>, user_agent: Option<String>) {
+        *self.live.client.lock() = (address, user_agent);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.sessions.finish(&self.live);
+    }
+}
+
+fn unix(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 0.01
+    }
+
+    #[test]
+    fn peak_is_the_most_sessions_at_once() {
+        let sessions = Sessions::default();
+
+        let mut live: Vec<Session> = (0..3).map(|_| sessions.start("opus")).collect();
+        live.truncate(1);
+        live.push(sessions.start("opus"));
+        assert_eq!(sessions.report().statistics.peak, 3);
+
+        live.extend((0..2).map(|_| sessions.start("flac")));
+        let report = sessions.report();
+        assert_eq!(report.statistics.peak, 4);
+        assert_eq!(report.active.len(), 4);
+        assert_eq!(report.recent.len(), 2);
+    }
+
+    #[test]
+    fn finished_sessions_add_up() {
+        let sessions = Sessions::default();
+        sessions.0.lock().listened = Duration::from_secs(3600);
+
+        for bytes in [1000, 3000].iter() {
+            let session = sessions.start("opus");
+            session.sent(*bytes);
+        }
+
+        let statistics = sessions.report().statistics;
+        assert_eq!(statistics.sessions, 2);
+        assert_eq!(statistics.bytes, 4000);
+        assert!(close(statistics.listening_hours, 1.0));
+        assert!(close(statistics.average_session, 1800.0));
+    }
+
+    #[test]
+    fn statistics_survive_a_restart() {
+        let directory = std::env::temp_dir().join(format!("quartz-sessions-{}", rand::random::<u64>()));
+        let path = directory.join("main.json");
+
+        let sessions = Sessions::default();
+        sessions.0.lock().listened = Duration::from_secs(2 * 3600);
+        drop(sessions.start("opus"));
+        let active = sessions.start("opus");
+        active.sent(500);
+        sessions.save(&path).unwrap();
+
+        // nothing is left aside once the file is in place
+        let mut files: Vec<_> = std::fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        files.sort();
+        assert_eq!(files, vec!["main.json"]);
+
+        let restored = Sessions::default();
+        restored.load(&path).unwrap();
+        let report = restored.report();
+        assert_eq!(report.statistics, sessions.report().statistics);
+        assert!(report.active.is_empty());
+        assert_eq!(report.recent.len(), 1);
+
+        // the ids and the listening time go on from the saved ones
+        drop(restored.start("opus"));
+        let report = restored.report();
+        assert_eq!(report.recent[1].id, report.recent[0].id + 1);
+        assert!(close(report.statistics.listening_hours, 2.0));
+        assert!(close(report.statistics.average_session, 3600.0));
+
+        // a missing file is a fresh start
+        let fresh = Sessions::default();
+        fresh.load(&directory.join("other.json")).unwrap();
+        assert_eq!(fresh.report(), SessionReport::default());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
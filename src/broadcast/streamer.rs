@@ -1,153 +1,1084 @@
-use std::collections::VecDeque;
-use std::collections::vec_deque::Iter;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicU32, AtomicU64};
 use std::sync::atomic::Ordering::Relaxed;
-use std::time::Duration;
+use std::ops::RangeInclusive;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread;
 
 use bytes::Bytes;
+use parking_lot::Mutex;
 use rocket::{response, Request};
 use rocket::response::stream::ReaderStream;
 use rocket::futures::StreamExt;
 use rocket::http::*;
 
-use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{oneshot, watch};
+use serde::{Serialize, Deserialize};
 
-use crate::AudioSource;
-use crate::broadcast::codec::Page;
-use crate::broadcast::Options;
-use crate::broadcast::pump::Pump;
+use crate::{metrics, AudioFormat, AudioSource, Track};
+use crate::metrics::{Exposition, Histogram};
+use crate::events::{EventHandle, EventStream};
+use crate::broadcast::codec::{Codec, EncoderSettings, StreamEncoder};
+use crate::broadcast::icy::{self, StationInfo};
+use crate::broadcast::pump::{Clock, Pump, PumpStats, SystemClock};
+use crate::broadcast::listener::{self, Overflow};
+use crate::broadcast::ring::{self, Item, Ring, Timeshift};
+use crate::broadcast::sessions::{Session, Sessions};
+
+/// Length of a sample block pulled from the source on each pump iteration.
+const BLOCK_SIZE: Duration = Duration::from_millis(20);
+
+/// Named encoder profile. Every tier has its own page buffer and its own set of listeners.
+#[derive(Clone, Debug)]
+pub struct Tier {
+    pub name: String,
+    pub codec: Codec,
+    /// Maximum amount of audio queued for a single listener, must exceed the codec's buffer size
+    pub max_lag: Duration,
+    /// Smallest burst a client can ask for, the largest (and the default) one is the codec's buffer size
+    pub min_burst: Duration,
+    /// History for the listeners starting in the past, if any
+    pub timeshift: Option<Timeshift>,
+    pub overflow: Overflow
+}
+
+/// Announces the encoder settings of a tier after they have been changed at runtime.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct EncoderChange {
+    pub tier: String,
+    pub settings: EncoderSettings
+}
+
+/// Request to the broadcast thread.
+enum Control {
+    /// Changes the encoder settings of a tier
+    Reconfigure {
+        tier: usize,
+        settings: EncoderSettings,
+        result: oneshot::Sender<anyhow::Result<EncoderSettings>>
+    },
+
+    /// Fades the audio out and ends all the streams, answered once the last pages are out
+    Shutdown {
+        fade: Duration,
+        done: oneshot::Sender<()>
+    }
+}
+
+/// Fade out in progress.
+struct Fade {
+    length: Duration,
+    remaining: Duration,
+    done: oneshot::Sender<()>
+}
+
+/// Consumer of the raw broadcast audio, driven by the broadcast thread.
+pub trait Sink: Send {
+    fn push(&mut self, samples: &[f32]) -> anyhow::Result<()>;
+
+    /// Notifies the sink that a new track has started playing.
+    fn track(&mut self, _track: &Track) {}
+
+    /// Ends the output, the sink gets no more samples afterwards.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Consecutive source errors are retried after a growing delay, up to this one.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Number of errors kept in the history.
+const ERROR_HISTORY: usize = 64;
 
 /// Broadcasts the audio source and manages connected client's output streams.
-pub fn run<S: AudioSource + 'static>(mut source: S, options: Options) -> anyhow::Result<StreamManager> {
-    let (sender, mut receiver) = unbounded_channel::<UnboundedSender<Bytes>>();
-    let mut pump = Pump::new(source.format(), &options)?;
+/// The first tier is used by default if the client does not specify one.
+pub fn run<S: AudioSource + 'static>(
+    source: S,
+    tiers: Vec<Tier>,
+    sinks: Vec<Box<dyn Sink>>,
+    tracks: EventStream<Track>,
+    info: StationInfo
+) -> anyhow::Result<StreamManager> {
+    run_with_clock(source, tiers, sinks, tracks, info, SystemClock)
+}
+
+/// Same as `run`, with the audio paced by the clock, e.g. a `ManualClock` to broadcast faster than real time.
+pub fn run_with_clock<S: AudioSource + 'static, C: Clock + 'static>(
+    source: S,
+    tiers: Vec<Tier>,
+    sinks: Vec<Box<dyn Sink>>,
+    tracks: EventStream<Track>,
+    info: StationInfo,
+    clock: C
+) -> anyhow::Result<StreamManager> {
+    if tiers.is_empty() {
+        return Err(anyhow::Error::msg("no tiers specified"));
+    }
+
+    if let Some(tier) = tiers.iter().find(|tier| tier.max_lag <= tier.codec.buffer_size()) {
+        return Err(anyhow::Error::msg(format!("max lag of tier {} does not exceed its buffer size", tier.name)));
+    }
+
+    let (control, control_receiver) = unbounded_channel::<Control>();
+    let (changes, changes_handle) = EventStream::new();
+
+    let buffer_size = tiers.iter().map(|tier| tier.codec.buffer_size()).max().unwrap_or_default();
+    let pump = Pump::with_clock(source.format(), BLOCK_SIZE, buffer_size, clock);
+    let pump_stats = pump.stats();
+    let mut outputs = Vec::with_capacity(tiers.len());
+    let mut infos = Vec::with_capacity(tiers.len());
+
+    for tier in tiers {
+        // the ring has to hold the burst as well as the pages of the slowest listeners
+        let (writer, ring) = ring::channel(&tier.name, tier.max_lag.max(tier.codec.buffer_size()), tier.timeshift.as_ref())?;
+        let output = Output::new(source.format(), &tier.codec, writer)?;
 
-    let counter = Arc::new(AtomicUsize::new(0));
-    let counter_pushthread = counter.clone();
+        infos.push(TierInfo {
+            bit_rate: AtomicU32::new(tier.codec.bit_rate().unwrap_or(0)),
+            content_type: output.encoder.content_type(),
+            burst: tier.min_burst..=tier.codec.buffer_size(),
+            timeshift: tier.timeshift.as_ref().map_or(Duration::ZERO, |timeshift| timeshift.length),
+            max_lag: tier.max_lag,
+            overflow: tier.overflow,
+            name: tier.name,
+            ring,
+            encode_time: Histogram::new(metrics::ENCODE_BUCKETS)
+        });
 
-    thread::spawn(move || {
-        let mut streams = Vec::new();
-        let mut queue = PageQueue::new(options.buffer_size);
+        outputs.push(output);
+    }
+
+    let tiers: Arc<[TierInfo]> = infos.into();
+    let errors = Errors::default();
+
+    let format = source.format();
+    let mut broadcaster = Broadcaster {
+        format,
+        source, pump, outputs, sinks,
+        tiers: tiers.clone(),
+        tracks: tracks.clone(),
+        control: control_receiver,
+        changes: changes_handle,
+        errors: errors.clone(),
+        fade: None,
+        faded: Vec::new()
+    };
+
+    thread::spawn(move || broadcaster.supervise());
+
+    Ok(StreamManager {
+        format,
+        control,
+        errors,
+        closing: Arc::new(watch::channel(false).0),
+        pump: pump_stats,
+        sessions: Sessions::default(),
+        holders: Arc::new(Mutex::new(HashMap::new())),
+        changes,
+        info: Arc::new(info),
+        tracks,
+        tiers
+    })
+}
+
+/// Error that has occurred in the broadcast thread.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Failure {
+    /// Unix time, in seconds
+    pub time: u64,
+    /// Tier whose encoder has failed, none for the source and the thread itself
+    pub tier: Option<String>,
+    pub message: String,
+    /// Whether the encoder has been restarted, with a new stream header
+    pub restarted: bool
+}
+
+/// Most recent errors of the broadcast thread.
+#[derive(Clone, Default)]
+struct Errors(Arc<Mutex<VecDeque<Failure>>>);
+
+impl Errors {
+
+    fn push(&self, tier: Option<&str>, message: String, restarted: bool) {
+        match tier {
+            Some(tier) => eprintln!("encoder error ({}): {}", tier, message),
+            None => eprintln!("audio thread error: {}", message)
+        }
+
+        let mut errors = self.0.lock();
+        if errors.len() == ERROR_HISTORY {
+            errors.pop_front();
+        }
+
+        errors.push_back(Failure {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            tier: tier.map(str::to_string),
+            message,
+            restarted
+        });
+    }
+}
+
+/// What the broadcast thread does after a step.
+enum Step {
+    Continue,
+    /// The source has failed, retry after a while
+    Retry,
+    /// The stream manager is gone
+    Exit
+}
+
+/// State of the broadcast thread.
+struct Broadcaster<S: AudioSource, C: Clock> {
+    format: AudioFormat,
+    source: S,
+    pump: Pump<C>,
+    outputs: Vec<Output>,
+    sinks: Vec<Box<dyn Sink>>,
+    tiers: Arc<[TierInfo]>,
+    tracks: EventStream<Track>,
+    control: UnboundedReceiver<Control>,
+    changes: EventHandle<EncoderChange>,
+    errors: Errors,
+    fade: Option<Fade>,
+    // faded copy of the current block
+    faded: Vec<f32>
+}
+
+impl<S: AudioSource, C: Clock> Broadcaster<S, C> {
+
+    /// Runs the broadcast until the stream manager is dropped, restarting the encoders on failures.
+    fn supervise(&mut self) {
+        let mut backoff = BLOCK_SIZE;
 
         loop {
-            // get the next page
-            let page = match pump.run(&mut source) {
-                Ok(Some(page)) => page,
-                Ok(None) => break,
-                Err(e) => {
-                    eprintln!("audio thread error: {}", e);
-                    continue;
+            match panic::catch_unwind(AssertUnwindSafe(|| self.step())) {
+                Ok(Step::Continue) => backoff = BLOCK_SIZE,
+                Ok(Step::Retry) => {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                },
+
+                Ok(Step::Exit) => break,
+                Err(panic) => {
+                    let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown panic".to_string());
+
+                    // the encoders might have been left in the middle of a page
+                    self.errors.push(None, format!("broadcast thread panicked: {}", message), false);
+                    for index in 0..self.outputs.len() {
+                        self.restart(index);
+                    }
                 }
+            }
+        }
+    }
+
+    fn step(&mut self) -> Step {
+        // get the next block of samples
+        let block = match self.pump.run(&mut self.source) {
+            Ok(block) => block,
+            Err(e) => {
+                self.errors.push(None, e.to_string(), false);
+                return Step::Retry;
+            }
+        };
+
+        // notify the encoders about the track change
+        if let Some(track) = self.tracks.try_poll() {
+            for output in self.outputs.iter_mut() {
+                output.encoder.track(&track);
+            }
+
+            for sink in self.sinks.iter_mut() {
+                sink.track(&track);
+            }
+        }
+
+        // apply the encoder settings changes, between the frames
+        loop {
+            let request = match self.control.try_recv() {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Step::Exit
             };
 
-            // accept new connections
-            loop {
-                match receiver.try_recv() {
-                    Ok(stream) => {
-                        let _ = stream.send(pump.header().clone());
+            match request {
+                Control::Reconfigure { tier, settings, result } => {
+                    let settings = self.outputs[tier].configure(&settings);
 
-                        for page in queue.iter() {
-                            let _ = stream.send(page.data.clone());
+                    if let Ok(settings) = &settings {
+                        let tier = &self.tiers[tier];
+                        if let Some(bit_rate) = settings.bit_rate {
+                            tier.bit_rate.store(bit_rate as u32 / 1000, Relaxed);
                         }
 
-                        streams.push(stream);
-                    },
+                        self.changes.send(EncoderChange {
+                            tier: tier.name.clone(),
+                            settings: settings.clone()
+                        });
+                    }
 
-                    Err(TryRecvError::Empty) => break,
-                    Err(TryRecvError::Disconnected) => return
+                    let _ = result.send(settings);
+                },
+
+                Control::Shutdown { fade, done } => if self.fade.is_none() {
+                    self.fade = Some(Fade { length: fade, remaining: fade, done });
                 }
             }
+        }
 
-            // put the page into the queue
-            queue.push(page.clone());
+        let block = match &mut self.fade {
+            Some(fade) => {
+                apply_fade(fade, block, &mut self.faded, self.format);
+                &self.faded[..]
+            },
 
-            // and send it to the clients
-            streams.retain_mut(|stream| {
-                stream.send(page.data.clone()).is_ok()
-            });
+            None => block
+        };
 
-            // update stream counter
-            counter_pushthread.store(streams.len(), Relaxed);
+        // encode the samples and publish the resulting pages to the listeners
+        let mut failed = Vec::new();
+        for (index, output) in self.outputs.iter_mut().enumerate() {
+            let started = Instant::now();
+            if let Err(e) = output.push(block) {
+                failed.push((index, e));
+            }
+
+            self.tiers[index].encode_time.observe(started.elapsed());
         }
-    });
 
-    Ok(StreamManager {
-        registrar: sender,
-        counter
-    })
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.push(block) {
+                eprintln!("sink error: {}", e);
+            }
+        }
+
+        for (index, e) in failed {
+            let restarted = self.restart(index);
+            self.errors.push(Some(&self.tiers[index].name), e.to_string(), restarted);
+        }
+
+        match self.fade.take() {
+            Some(fade) if fade.remaining.is_zero() => {
+                self.finish();
+                let _ = fade.done.send(());
+
+                Step::Exit
+            },
+
+            fade => {
+                self.fade = fade;
+                Step::Continue
+            }
+        }
+    }
+
+    /// Ends the streams of all the tiers and the sinks.
+    fn finish(&mut self) {
+        for (output, tier) in self.outputs.iter_mut().zip(self.tiers.iter()) {
+            if let Err(e) = output.finish() {
+                eprintln!("encoder error ({}): {}", tier.name, e);
+            }
+        }
+
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.finish() {
+                eprintln!("sink error: {}", e);
+            }
+        }
+    }
+
+    /// Replaces the encoder of the tier with a new one, returns whether it has succeeded.
+    fn restart(&mut self, index: usize) -> bool {
+        match self.outputs[index].restart(self.format) {
+            Ok(()) => true,
+            Err(e) => {
+                self.errors.push(Some(&self.tiers[index].name), format!("failed to restart the encoder: {}", e), false);
+                false
+            }
+        }
+    }
+}
+
+/// Copies the block into `faded` with the gain ramping down towards the end of the fade.
+fn apply_fade(fade: &mut Fade, block: &[f32], faded: &mut Vec<f32>, format: AudioFormat) {
+    let channels = format.channels as usize;
+    let frames = block.len() / channels.max(1);
+    let frame = Duration::from_secs(1) / format.sample_rate;
+    let length = fade.length.as_secs_f32();
+
+    faded.clear();
+    faded.extend_from_slice(block);
+
+    for (index, samples) in faded.chunks_mut(channels.max(1)).enumerate() {
+        let remaining = fade.remaining.saturating_sub(frame * index as u32);
+        let gain = if length > 0.0 { remaining.as_secs_f32() / length } else { 0.0 };
+
+        for sample in samples {
+            *sample *= gain;
+        }
+    }
+
+    fade.remaining = fade.remaining.saturating_sub(frame * frames as u32);
+}
+
+struct TierInfo {
+    name: String,
+    content_type: ContentType,
+    // kbps, 0 if there is no nominal bitrate
+    bit_rate: AtomicU32,
+    burst: RangeInclusive<Duration>,
+    // maximum timeshift offset
+    timeshift: Duration,
+    max_lag: Duration,
+    overflow: Overflow,
+    ring: Arc<Ring>,
+    // time spent encoding a block
+    encode_time: Histogram
 }
 
 #[derive(Clone)]
 pub struct StreamManager {
-    counter: Arc<AtomicUsize>,
-    registrar: UnboundedSender<UnboundedSender<Bytes>>
+    format: AudioFormat,
+    tiers: Arc<[TierInfo]>,
+    info: Arc<StationInfo>,
+    tracks: EventStream<Track>,
+    changes: EventStream<EncoderChange>,
+    control: UnboundedSender<Control>,
+    errors: Errors,
+    // set once the shutdown has started, no new listeners are accepted afterwards
+    closing: Arc<watch::Sender<bool>>,
+    sessions: Sessions,
+    // listener counts of the token holders
+    holders: Arc<Mutex<HashMap<String, usize>>>,
+    pump: Arc<PumpStats>
+}
+
+/// Reason a stream could not be opened.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub enum OpenError {
+    NoSuchTier(String),
+    /// The broadcast has stopped
+    Closed,
+    /// The token holder has as many listeners as they are allowed
+    HolderLimit
+}
+
+impl std::fmt::Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenError::NoSuchTier(tier) => write!(f, "no such tier: {}", tier),
+            OpenError::Closed => write!(f, "streamer closed"),
+            OpenError::HolderLimit => write!(f, "too many listeners for this token")
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl<'r> response::Responder<'r, 'static> for OpenError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = match self {
+            OpenError::NoSuchTier(_) => Status::NotFound,
+            OpenError::Closed => Status::ServiceUnavailable,
+            OpenError::HolderLimit => Status::TooManyRequests
+        };
+
+        response::Response::build_from(self.to_string().respond_to(req)?)
+            .status(status)
+            .ok()
+    }
 }
 
 impl StreamManager {
 
-    pub fn open(&self) -> Stream {
-        let (sender, receiver) = unbounded_channel();
-        self.registrar.send(sender).expect("streamer closed??");
-        Stream(receiver)
+    /// Opens a stream of the specified tier (or of the default one) with the default burst.
+    pub fn open(&self, tier: Option<&str>) -> Result<Stream, OpenError> {
+        self.open_with(tier, None, Duration::ZERO)
+    }
+
+    /// Opens a stream counting against the listener limit of the token holder, if they have one.
+    pub fn open_as(&self, holder: &str, max_listeners: Option<usize>, tier: Option<&str>, burst: Option<Duration>, offset: Duration) -> Result<Stream, OpenError> {
+        {
+            let mut holders = self.holders.lock();
+            let count = holders.entry(holder.to_string()).or_insert(0);
+
+            if max_listeners.is_some_and(|max| *count >= max) {
+                return Err(OpenError::HolderLimit);
+            }
+
+            *count += 1;
+        }
+
+        // counted from here on, so that a failure to open releases the slot as well
+        let slot = HolderSlot {
+            holders: self.holders.clone(),
+            holder: holder.to_string()
+        };
+
+        Ok(self.open_with(tier, burst, offset)?.hold(slot))
+    }
+
+    /// Opens a stream starting `offset` in the past with up to `burst` of audio sent right away.
+    /// Both are clamped to the bounds of the tier.
+    pub fn open_with(&self, tier: Option<&str>, burst: Option<Duration>, offset: Duration) -> Result<Stream, OpenError> {
+        self.open_stream(tier, burst, offset, None)
+    }
+
+    /// Opens a stream for a consumer within the station, e.g. the recorder or a relay,
+    /// which is neither counted as a listener nor given a session.
+    pub fn open_internal(&self, tier: Option<&str>, burst: Option<Duration>, overflow: Overflow) -> Result<Stream, OpenError> {
+        self.open_stream(tier, burst, Duration::ZERO, Some(overflow))
+    }
+
+    /// Opens a listener stream, or an internal one with its own overflow policy.
+    fn open_stream(&self, tier: Option<&str>, burst: Option<Duration>, offset: Duration, internal: Option<Overflow>) -> Result<Stream, OpenError> {
+        // the broadcast thread exits only once the control channel is closed
+        if self.control.is_closed() || *self.closing.borrow() {
+            return Err(OpenError::Closed);
+        }
+
+        let index = match tier {
+            Some(name) => self.tiers.iter().position(|tier| tier.name == name)
+                .ok_or_else(|| OpenError::NoSuchTier(name.to_string()))?,
+            None => 0
+        };
+
+        let tier = &self.tiers[index];
+        let burst = burst.map_or(*tier.burst.end(), |burst| burst.clamp(*tier.burst.start(), *tier.burst.end()));
+        let offset = offset.min(tier.timeshift);
+        let (receiver, session) = match internal {
+            Some(overflow) => (listener::Receiver::internal(tier.ring.clone(), tier.max_lag, overflow, burst, offset), None),
+            None => (listener::Receiver::new(tier.ring.clone(), tier.max_lag, tier.overflow, burst, offset), Some(self.sessions.start(&tier.name)))
+        };
+
+        Ok(Stream {
+            session,
+            guards: Vec::new(),
+            burst: receiver.burst(),
+            offset: receiver.offset(),
+            receiver,
+            content_type: tier.content_type.clone(),
+            bit_rate: match tier.bit_rate.load(Relaxed) {
+                0 => None,
+                bit_rate => Some(bit_rate)
+            },
+            info: self.info.clone(),
+            tracks: self.tracks.clone()
+        })
+    }
+
+    /// Listening sessions of all the tiers.
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    pub fn info(&self) -> &StationInfo {
+        &self.info
+    }
+
+    /// Track change notifications of the broadcast.
+    pub fn tracks(&self) -> EventStream<Track> {
+        self.tracks.clone()
+    }
+
+    /// Encoder settings change notifications.
+    pub fn changes(&self) -> EventStream<EncoderChange> {
+        self.changes.clone()
+    }
+
+    /// Changes the encoder settings of the tier without interrupting the stream.
+    pub async fn configure(&self, tier: &str, settings: EncoderSettings) -> anyhow::Result<EncoderSettings> {
+        settings.validate(self.format)?;
+
+        let index = self.tiers.iter().position(|info| info.name == tier)
+            .ok_or_else(|| anyhow::Error::msg(format!("no such tier: {}", tier)))?;
+
+        let (result, receiver) = oneshot::channel();
+        self.control.send(Control::Reconfigure { tier: index, settings, result })
+            .map_err(|_| anyhow::Error::msg("streamer closed"))?;
+
+        receiver.await.map_err(|_| anyhow::Error::msg("streamer closed"))?
+    }
+
+    /// Stops accepting listeners, fades the audio out and ends the streams with the end-of-stream pages.
+    /// Returns once the last pages have been published, the listeners can still be receiving them.
+    pub async fn shutdown(&self, fade: Duration) {
+        self.closing.send_replace(true);
+
+        let (done, receiver) = oneshot::channel();
+        if self.control.send(Control::Shutdown { fade, done }).is_ok() {
+            let _ = receiver.await;
+        }
+    }
+
+    /// Resolves once the shutdown has started.
+    pub async fn closing(&self) {
+        let mut closing = self.closing.subscribe();
+        while !*closing.borrow_and_update() {
+            if closing.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Most recent errors of the broadcast thread, oldest first.
+    pub fn errors(&self) -> Vec<Failure> {
+        self.errors.0.lock().iter().cloned().collect()
     }
 
+    /// Total listener count across all the tiers.
     pub fn count(&self) -> usize {
-        self.counter.load(Relaxed)
+        self.tiers.iter().map(|tier| tier.ring.stats.listeners.load(Relaxed)).sum()
+    }
+
+    /// Listener count of each tier.
+    pub fn counts(&self) -> BTreeMap<String, usize> {
+        self.tiers.iter()
+            .map(|tier| (tier.name.clone(), tier.ring.stats.listeners.load(Relaxed)))
+            .collect()
+    }
+
+    /// Adds the metrics of all the tiers, labeled with the station.
+    pub fn metrics(&self, station: &str, exposition: &mut Exposition) {
+        for tier in self.tiers.iter() {
+            let labels = [("station", station), ("tier", tier.name.as_str())];
+            let stats = &tier.ring.stats;
+
+            exposition.gauge("quartz_listeners", "Connected listeners", &labels, stats.listeners.load(Relaxed) as f64);
+            exposition.gauge("quartz_listeners_lagging", "Listeners over half of their maximum lag behind", &labels, stats.lagging.load(Relaxed) as f64);
+            exposition.counter("quartz_pages_total", "Pages published by the encoder", &labels, stats.pages.load(Relaxed) as f64);
+            exposition.counter("quartz_pages_sent_total", "Pages sent to the listeners", &labels, stats.pages_sent.load(Relaxed) as f64);
+            exposition.counter("quartz_bytes_sent_total", "Bytes sent to the listeners", &labels, stats.bytes_sent.load(Relaxed) as f64);
+            exposition.counter("quartz_pages_dropped_total", "Pages skipped by the listeners that fell behind", &labels, stats.dropped.load(Relaxed) as f64);
+            exposition.counter("quartz_listeners_overflowed_total", "Listeners disconnected for falling behind", &labels, stats.overflowed.load(Relaxed) as f64);
+            exposition.histogram("quartz_encode_seconds", "Time to encode a block of samples", &labels, &tier.encode_time);
+        }
+
+        let labels = [("station", station)];
+        let nanos = |value: &AtomicU64| value.load(Relaxed) as f64 / 1e9;
+
+        exposition.gauge("quartz_pump_drift_seconds", "How late the audio pump pulled the last block", &labels, nanos(&self.pump.drift));
+        exposition.counter("quartz_pump_skipped_seconds_total", "Audio skipped by the pump after falling behind", &labels, nanos(&self.pump.skipped));
+        exposition.counter("quartz_pump_corrections_total", "Times the audio pump skipped ahead after falling behind", &labels, self.pump.corrections.load(Relaxed) as f64);
+    }
+
+    /// Backpressure statistics of each tier.
+    pub fn lag(&self) -> BTreeMap<String, LagStats> {
+        self.tiers.iter()
+            .map(|tier| (tier.name.clone(), LagStats {
+                lagging: tier.ring.stats.lagging.load(Relaxed),
+                dropped: tier.ring.stats.dropped.load(Relaxed),
+                overflowed: tier.ring.stats.overflowed.load(Relaxed)
+            }))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq, Debug, Hash)]
+pub struct LagStats {
+    /// Listeners that are over half of their maximum lag behind
+    pub lagging: usize,
+    /// Pages skipped by the listeners that fell behind so far
+    pub dropped: u64,
+    /// Listeners disconnected for falling behind so far
+    pub overflowed: u64
+}
+
+pub struct Stream {
+    receiver: listener::Receiver,
+    // none for the internal streams
+    session: Option<Session>,
+    // released along with the stream, e.g. connection permits
+    guards: Vec<Box<dyn std::any::Any + Send + Sync>>,
+    burst: Duration,
+    offset: Duration,
+    content_type: ContentType,
+    bit_rate: Option<u32>,
+    info: Arc<StationInfo>,
+    tracks: EventStream<Track>
+}
+
+impl Stream {
+
+    /// Receives the next chunk of the stream, for consumers other than the HTTP response.
+    pub async fn next(&mut self) -> Option<Bytes> {
+        let data = self.receiver.recv().await?;
+        if let Some(session) = &self.session {
+            session.sent(data.len());
+        }
+
+        Some(data)
+    }
+
+    /// Stream time at the end of the last page received, see `listener::Receiver::position`.
+    pub fn position(&self) -> Duration {
+        self.receiver.position()
+    }
+
+    /// Resends the stream header before the next page.
+    pub fn resync(&mut self) {
+        self.receiver.resync();
+    }
+
+    /// Keeps the guard alive for as long as the stream.
+    pub fn hold<T: Send + Sync + 'static>(mut self, guard: T) -> Self {
+        self.guards.push(Box::new(guard));
+        self
+    }
+
+    /// Records who the listener is in the session statistics.
+    pub fn identify(&self, address: Option<IpAddr>, user_agent: Option<String>) {
+        if let Some(session) = &self.session {
+            session.identify(address, user_agent);
+        }
+    }
+
+    pub fn content_type(&self) -> &ContentType {
+        &self.content_type
+    }
+
+    /// Nominal bitrate in kbps, if there is one.
+    pub fn bit_rate(&self) -> Option<u32> {
+        self.bit_rate
+    }
+
+    /// Amount of audio sent right away on connect, on top of the offset.
+    pub fn burst(&self) -> Duration {
+        self.burst
+    }
+
+    /// How far in the past the stream has started, not counting the burst.
+    pub fn offset(&self) -> Duration {
+        self.offset
     }
 }
 
-pub struct Stream(UnboundedReceiver<Bytes>);
 impl<'r> response::Responder<'r, 'r> for Stream
 {
-    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'r> {
-        response::Response::build()
-            .header(ContentType::new("audio", "ogg"))
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let icy = req.headers().get_one("Icy-MetaData").is_some_and(|value| value.trim() == "1");
+        self.identify(req.client_ip(), req.headers().get_one("User-Agent").map(str::to_string));
+
+        let mut response = response::Response::build();
+
+        response
+            .header(self.content_type)
             .header(Header::new("Access-Control-Allow-Origin", "*"))
             .header(Header::new("Connection", "close"))
             .header(Header::new("Cache-Control", "no-cache, no-store"))
             .header(Header::new("Pragma", "no-cache"))
             .header(Header::new("Expires", "0"))
-            .streamed_body(ReaderStream::from(UnboundedReceiverStream::new(self.0).map(std::io::Cursor::new)))
-            .ok()
+            // in milliseconds, so that players can work out how far behind live they are
+            .header(Header::new("X-Burst-Length", self.burst.as_millis().to_string()))
+            .header(Header::new("X-Timeshift-Offset", self.offset.as_millis().to_string()))
+            .header(Header::new("Access-Control-Expose-Headers", "X-Burst-Length, X-Timeshift-Offset"))
+            .header(Header::new("icy-name", self.info.name.clone()))
+            .header(Header::new("icy-genre", self.info.genre.clone()))
+            .header(Header::new("icy-description", self.info.description.clone()))
+            .header(Header::new("icy-url", self.info.url.clone()));
+
+        if let Some(bit_rate) = self.bit_rate {
+            response.header(Header::new("icy-br", bit_rate.to_string()));
+        }
+
+        // the session ends along with the body
+        let (session, guards) = (self.session, self.guards);
+        let sent = move |data: Bytes| {
+            let _ = &guards;
+            if let Some(session) = &session {
+                session.sent(data.len());
+            }
+
+            std::io::Cursor::new(data)
+        };
+
+        if icy {
+            response
+                .header(Header::new("icy-metaint", icy::METAINT.to_string()))
+                .streamed_body(ReaderStream::from(icy::interleave(self.receiver, self.tracks).map(sent)));
+        } else {
+            let mut receiver = self.receiver;
+            let stream = async_stream::stream! {
+                while let Some(data) = receiver.recv().await {
+                    yield data;
+                }
+            };
+
+            response.streamed_body(ReaderStream::from(stream.map(sent)));
+        }
+
+        response.ok()
     }
 }
 
-struct PageQueue {
-    queue: VecDeque<Page>,
-    length: Duration,
-    max_length: Duration,
+/// Listener of a token holder, counted until dropped.
+struct HolderSlot {
+    holders: Arc<Mutex<HashMap<String, usize>>>,
+    holder: String
 }
 
-impl PageQueue {
+impl Drop for HolderSlot {
+    fn drop(&mut self) {
+        let mut holders = self.holders.lock();
 
-    fn new(max_length: Duration) -> Self {
-        Self {
-            queue: VecDeque::new(),
-            length: Duration::from_nanos(0),
-            max_length
+        if let Some(count) = holders.get_mut(&self.holder) {
+            *count -= 1;
+            if *count == 0 {
+                holders.remove(&self.holder);
+            }
         }
     }
+}
 
-    fn push(&mut self, page: Page) {
-        while self.length > self.max_length {
-            match self.queue.pop_front() {
-                Some(page) => {
-                    self.length -= page.duration;
-                },
+/// Encoder output of a single tier.
+struct Output {
+    codec: Codec,
+    encoder: Box<dyn StreamEncoder>,
+    header: Bytes,
+    ring: ring::Writer,
+    // settings changed at runtime, reapplied on restart
+    settings: Option<EncoderSettings>
+}
+
+impl Output {
+
+    fn new(format: AudioFormat, codec: &Codec, ring: ring::Writer) -> anyhow::Result<Self> {
+        let encoder = codec.encoder(format)?;
+
+        Ok(Self {
+            codec: codec.clone(),
+            header: encoder.header().clone(),
+            encoder,
+            ring,
+            settings: None
+        })
+    }
+
+    fn configure(&mut self, settings: &EncoderSettings) -> anyhow::Result<EncoderSettings> {
+        let settings = self.encoder.configure(settings)?;
+        self.settings = Some(settings.clone());
+
+        Ok(settings)
+    }
+
+    /// Starts a new stream with a fresh encoder. The listeners get the end of the current stream
+    /// and the new header before the next page if the format can carry on,
+    /// otherwise their streams end so that they reconnect.
+    fn restart(&mut self, format: AudioFormat) -> anyhow::Result<()> {
+        let mut encoder = self.codec.encoder(format)?;
+        if let Some(settings) = &self.settings {
+            encoder.configure(settings)?;
+        }
 
-                None => panic!("broadcast buffer: queue invariant broken?")
+        if self.codec.chainable() {
+            // the old encoder might be what has failed, in which case the stream is left unterminated
+            match panic::catch_unwind(AssertUnwindSafe(|| self.finish())) {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => eprintln!("failed to end the stream before the restart: {}", e),
+                Err(_) => eprintln!("failed to end the stream before the restart: the encoder has panicked")
             }
+        } else {
+            self.ring.reset();
+        }
+
+        self.header = encoder.header().clone();
+        self.encoder = encoder;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(page) = self.encoder.finish()? {
+            self.ring.push(Item {
+                page,
+                header: self.header.clone(),
+                next_header: self.header.clone()
+            });
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        if let Some(page) = self.encoder.push(samples)? {
+            // the header might have changed since the page has started
+            let next_header = self.encoder.header().clone();
+
+            self.ring.push(Item {
+                page,
+                header: std::mem::replace(&mut self.header, next_header.clone()),
+                next_header
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::broadcast::codec::ogg_tests::{self, BOS, EOS};
+    use crate::broadcast::codec::test_options;
+    use crate::broadcast::pump::ManualClock;
+
+    pub const FORMAT: AudioFormat = AudioFormat {
+        channels: 2,
+        sample_rate: 48000
+    };
+
+    pub struct Silence;
+
+    impl AudioSource for Silence {
+        fn format(&self) -> AudioFormat {
+            FORMAT
+        }
+
+        fn pull(&mut self, samples: &mut [f32]) -> anyhow::Result<usize> {
+            samples.fill(0.0);
+            Ok(samples.len())
+        }
+    }
+
+    /// Broadcasts silence in real time on a single Opus tier named `opus`, with 200ms pages.
+    pub fn silent_station(tracks: EventStream<Track>) -> StreamManager {
+        silent_station_with_clock(tracks, SystemClock)
+    }
+
+    fn silent_station_with_clock<C: Clock + 'static>(tracks: EventStream<Track>, clock: C) -> StreamManager {
+        let tier = Tier {
+            name: "opus".to_owned(),
+            codec: Codec::Opus(test_options(Duration::from_millis(200))),
+            max_lag: Duration::from_secs(10),
+            min_burst: Duration::ZERO,
+            timeshift: None,
+            overflow: Overflow::Disconnect
+        };
+
+        let info = StationInfo {
+            name: "Quartz".to_owned(),
+            genre: "Ambient".to_owned(),
+            description: "Test station".to_owned(),
+            url: "https://example.com".to_owned()
+        };
+
+        run_with_clock(Silence, vec![tier], Vec::new(), tracks, info, clock).unwrap()
+    }
+
+    #[rocket::get("/listen?<burst>")]
+    fn listen(streams: &rocket::State<StreamManager>, burst: u64) -> Result<Stream, OpenError> {
+        streams.open_with(None, Some(Duration::from_millis(burst)), Duration::ZERO)
+    }
+
+    #[test]
+    fn burst_is_clamped_to_the_buffer_and_to_whole_pages() {
+        let (tracks, _handle) = EventStream::new();
+        let streams = silent_station_with_clock(tracks, ManualClock::new());
+
+        let started = Instant::now();
+        while streams.tiers[0].ring.read().position(u64::MAX) < Duration::from_secs(10) {
+            assert!(started.elapsed() < Duration::from_secs(20), "broadcast not running ahead");
+            thread::sleep(Duration::from_millis(10));
         }
 
-        self.length += page.duration;
-        self.queue.push_back(page);
+        // the buffer of the tier is 7s long, the pages 200ms
+        let burst = |burst: Duration| streams.open_with(None, Some(burst), Duration::ZERO).unwrap().burst();
+        assert_eq!(burst(Duration::from_secs(20)), Duration::from_secs(7));
+        assert_eq!(burst(Duration::from_millis(1100)), Duration::from_secs(1));
+        assert_eq!(streams.open(None).unwrap().burst(), Duration::from_secs(7));
+
+        let rocket = rocket::build()
+            .manage(streams)
+            .mount("/", rocket::routes![listen]);
+
+        let client = rocket::local::blocking::Client::tracked(rocket).unwrap();
+        for (requested, actual) in [(20000, "7000"), (1100, "1000")].iter() {
+            let response = client.get(rocket::uri!(listen(*requested))).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.headers().get_one("X-Burst-Length"), Some(*actual));
+        }
     }
 
-    fn iter(&self) -> Iter<Page> {
-        self.queue.iter()
+    #[test]
+    fn manual_clock_runs_faster_than_real_time() {
+        let clock = ManualClock::new();
+        let (tracks, _handle) = EventStream::new();
+        let streams = silent_station_with_clock(tracks, clock.clone());
+
+        let head = || streams.tiers[0].ring.read().position(u64::MAX);
+        let started = Instant::now();
+        while head() < Duration::from_secs(60) {
+            assert!(started.elapsed() < Duration::from_secs(20), "broadcast not running ahead");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(streams.pump.corrections.load(Relaxed), 0);
+
+        // a stall longer than the buffer is skipped
+        clock.advance(Duration::from_secs(60));
+        while streams.pump.corrections.load(Relaxed) == 0 {
+            assert!(started.elapsed() < Duration::from_secs(20), "stall not noticed");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let skipped = Duration::from_nanos(streams.pump.skipped.load(Relaxed));
+        assert!(skipped > Duration::from_secs(52) && skipped <= Duration::from_secs(53), "{:?}", skipped);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn restart_ends_the_chained_stream_first() {
+        let (writer, ring) = ring::channel("test", Duration::from_secs(60), None).unwrap();
+        let mut output = Output::new(FORMAT, &Codec::Opus(test_options(Duration::from_millis(200))), writer).unwrap();
+
+        let second = vec![0.0; 2 * 48000];
+        output.push(&second).unwrap();
+        output.restart(FORMAT).unwrap();
+        output.push(&second).unwrap();
+        drop(output);
+
+        // as received by a listener from the very beginning
+        let mut receiver = listener::Receiver::new(ring.clone(), Duration::from_secs(60), Overflow::Disconnect, Duration::from_secs(60), Duration::ZERO);
+        let mut data = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            data.extend_from_slice(&chunk);
+        }
+
+        let pages = ogg_tests::pages(&data);
+        assert_eq!(pages[0].flags, BOS);
+
+        let end = pages.iter().position(|page| page.flags & EOS != 0).expect("no end of stream");
+        assert_eq!(pages[end].serial, pages[0].serial);
+        assert_eq!(pages[end].granule, 48000);
+
+        assert_eq!(pages[end + 1].flags, BOS);
+        assert_ne!(pages[end + 1].serial, pages[0].serial);
+        assert!(pages[end + 1..].iter().all(|page| page.serial == pages[end + 1].serial && page.flags & EOS == 0));
+
+        // the listeners carry on
+        assert_eq!(ring.read().generation(), 0);
+    }
+
+    #[test]
+    fn restart_ends_the_streams_that_can_not_carry_on() {
+        let (writer, ring) = ring::channel("test", Duration::from_secs(60), None).unwrap();
+        let mut output = Output::new(FORMAT, &Codec::WebM(test_options(Duration::from_millis(200))), writer).unwrap();
+
+        output.push(&vec![0.0; 2 * 48000]).unwrap();
+        assert!(ring.read().next() > 0);
+
+        output.restart(FORMAT).unwrap();
+
+        let state = ring.read();
+        assert_eq!(state.generation(), 1);
+        assert!(state.get(state.next() - 1).is_none());
+    }
+}
@@ -13,10 +13,23 @@ impl<T, U> Join<T, U> {
     pub fn new(first: EventStream<T>, second: EventStream<U>) -> Self {
         Self(first, second)
     }
+
+    pub fn join<V>(self, with: EventStream<V>) -> Join3<T, U, V> {
+        Join3(self.0, self.1, with)
+    }
+}
+
+pub struct Join3<T, U, V>(EventStream<T>, EventStream<U>, EventStream<V>);
+
+impl<T, U, V> Clone for Join3<T, U, V> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), self.1.clone(), self.2.clone())
+    }
 }
 
 use rocket::response;
 use rocket::Request;
+use crate::metrics;
 
 impl<'r, T: 'static + Send + Sync + serde::Serialize, U: 'static + Send + Sync + serde::Serialize> response::Responder<'r, 'r> for Join<T, U> {
     fn respond_to(mut self, req: &'r Request<'_>) -> response::Result<'r> {
@@ -25,6 +38,8 @@ impl<'r, T: 'static + Send + Sync + serde::Serialize, U: 'static + Send + Sync +
         use either::Either;
 
         let stream = async_stream::stream! {
+            let _subscriber = metrics::SSE_SUBSCRIBERS.track();
+
             if let Some(data) = self.0.current() {
                 yield SSEEvent::json(data.deref());
             }
@@ -35,8 +50,10 @@ impl<'r, T: 'static + Send + Sync + serde::Serialize, U: 'static + Send + Sync +
 
             loop {
                 let result = tokio::select! {
-                    Some(data) = self.0.poll() => Either::Left(data), //TODO exit condition
-                    Some(data) = self.1.poll() => Either::Right(data)
+                    Some(data) = self.0.poll() => Either::Left(data),
+                    Some(data) = self.1.poll() => Either::Right(data),
+                    // all the senders are gone, the station has shut down
+                    else => break
                 };
 
                 match result {
@@ -49,3 +66,43 @@ impl<'r, T: 'static + Send + Sync + serde::Serialize, U: 'static + Send + Sync +
         SSEStream::from(stream).respond_to(req)
     }
 }
+
+impl<'r, T, U, V> response::Responder<'r, 'r> for Join3<T, U, V>
+    where T: 'static + Send + Sync + serde::Serialize,
+          U: 'static + Send + Sync + serde::Serialize,
+          V: 'static + Send + Sync + serde::Serialize
+{
+    fn respond_to(mut self, req: &'r Request<'_>) -> response::Result<'r> {
+        use rocket::response::stream::{Event as SSEEvent, EventStream as SSEStream};
+        use std::ops::Deref;
+
+        let stream = async_stream::stream! {
+            let _subscriber = metrics::SSE_SUBSCRIBERS.track();
+
+            if let Some(data) = self.0.current() {
+                yield SSEEvent::json(data.deref());
+            }
+
+            if let Some(data) = self.1.current() {
+                yield SSEEvent::json(data.deref());
+            }
+
+            if let Some(data) = self.2.current() {
+                yield SSEEvent::json(data.deref());
+            }
+
+            loop {
+                let event = tokio::select! {
+                    Some(data) = self.0.poll() => SSEEvent::json(data.deref()),
+                    Some(data) = self.1.poll() => SSEEvent::json(data.deref()),
+                    Some(data) = self.2.poll() => SSEEvent::json(data.deref()),
+                    else => break
+                };
+
+                yield event;
+            }
+        };
+
+        SSEStream::from(stream).respond_to(req)
+    }
+}
@@ -26,6 +26,15 @@ impl<T> EventStream<T> {
         self.0.borrow_and_update().clone()
     }
 
+    /// Returns the data if it has changed since the last check, without waiting.
+    pub fn try_poll(&mut self) -> Option<Arc<T>> {
+        if !self.0.has_changed().ok()? {
+            return None;
+        }
+
+        self.0.borrow_and_update().clone()
+    }
+
     pub async fn poll(&mut self) -> Option<Arc<T>> {
         loop {
             self.0.changed().await.ok()?;
@@ -55,12 +64,15 @@ impl<T> EventHandle<T> {
 
 use rocket::response;
 use rocket::Request;
+use crate::metrics;
 
 impl<'r, T: 'static + Send + Sync + serde::Serialize> response::Responder<'r, 'r> for EventStream<T> {
     fn respond_to(mut self, req: &'r Request<'_>) -> response::Result<'r> {
         use rocket::response::stream::{Event as SSEEvent, EventStream as SSEStream};
 
         let stream = async_stream::stream! {
+            let _subscriber = metrics::SSE_SUBSCRIBERS.track();
+
             if let Some(data) = self.current() {
                 yield SSEEvent::json(data.deref());
             }
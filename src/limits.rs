@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rocket::{response, Request};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+
+/// Suggested wait for the clients turned away because the server is full.
+const FULL_RETRY: Duration = Duration::from_secs(30);
+
+/// Suggested wait for the clients turned away for having too many connections.
+const TOO_MANY_RETRY: Duration = Duration::from_secs(10);
+
+/// Range of addresses, e.g. `192.168.0.0/16`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients of a dual stack socket show up as mapped IPv6 addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            v4 => v4
+        };
+
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_eq(u32::from(network) as u128, u32::from(address) as u128, 32, self.prefix)
+            },
+
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_eq(u128::from(network), u128::from(address), 128, self.prefix)
+            },
+
+            _ => false
+        }
+    }
+}
+
+fn prefix_eq(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift >= bits || a >> shift == b >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None)
+        };
+
+        let bits = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return Err(anyhow::Error::msg(format!("invalid prefix length in {}", s)));
+        }
+
+        // matched against the addresses the same way, i.e. as IPv4
+        if let IpAddr::V6(v6) = network {
+            if let (Some(v4), true) = (v6.to_ipv4_mapped(), prefix >= 96) {
+                return Ok(Self { network: IpAddr::V4(v4), prefix: prefix - 96 });
+            }
+        }
+
+        Ok(Self { network, prefix })
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct LimitOptions {
+    /// Listeners across all the stations
+    pub max_listeners: Option<usize>,
+    /// Connections from a single address
+    pub max_per_address: Option<usize>,
+    /// Ranges exempt from the per address limit, e.g. known relays
+    pub allow: Vec<Cidr>,
+    /// Ranges turned away, even if they are allowed as well
+    pub deny: Vec<Cidr>
+}
+
+impl LimitOptions {
+
+    /// Reads the limits from `MAX_LISTENERS`, `MAX_LISTENERS_PER_IP`,
+    /// and the comma separated `ALLOW_CIDRS` and `DENY_CIDRS`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let number = |name: &str| std::env::var(name).ok().map(|value| value.parse::<usize>()).transpose();
+        let ranges = |name: &str| std::env::var(name).ok()
+            .map_or(Ok(Vec::new()), |value| value.split(',')
+                .map(str::trim)
+                .filter(|range| !range.is_empty())
+                .map(Cidr::from_str)
+                .collect::<anyhow::Result<Vec<_>>>());
+
+        Ok(Self {
+            max_listeners: number("MAX_LISTENERS")?,
+            max_per_address: number("MAX_LISTENERS_PER_IP")?,
+            allow: ranges("ALLOW_CIDRS")?,
+            deny: ranges("DENY_CIDRS")?
+        })
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    addresses: HashMap<IpAddr, usize>
+}
+
+/// Admits the listeners within the limits.
+#[derive(Clone)]
+pub struct Limiter {
+    options: Arc<LimitOptions>,
+    counts: Arc<Mutex<Counts>>
+}
+
+/// Why a listener has been turned away.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub enum Rejection {
+    Denied,
+    Full,
+    TooMany
+}
+
+impl Limiter {
+
+    pub fn new(options: LimitOptions) -> Self {
+        Self {
+            options: Arc::new(options),
+            counts: Arc::new(Mutex::new(Counts::default()))
+        }
+    }
+
+    /// Lets the listener in, the connection counts until the permit is dropped.
+    pub fn admit(&self, address: Option<IpAddr>) -> Result<Permit, Rejection> {
+        let allowed = address.is_some_and(|address| self.options.allow.iter().any(|range| range.contains(address)));
+        let denied = address.is_some_and(|address| self.options.deny.iter().any(|range| range.contains(address)));
+
+        if denied {
+            return Err(Rejection::Denied);
+        }
+
+        let mut counts = self.counts.lock();
+        if self.options.max_listeners.is_some_and(|max| counts.total >= max) {
+            return Err(Rejection::Full);
+        }
+
+        // only the limited addresses are counted
+        let address = address.filter(|_| !allowed && self.options.max_per_address.is_some());
+        if let Some(address) = address {
+            // the rejected addresses must not leave an entry behind, there is no permit to remove it
+            let count = counts.addresses.get(&address).copied().unwrap_or(0);
+            if self.options.max_per_address.is_some_and(|max| count >= max) {
+                return Err(Rejection::TooMany);
+            }
+
+            counts.addresses.insert(address, count + 1);
+        }
+
+        counts.total += 1;
+
+        Ok(Permit {
+            counts: self.counts.clone(),
+            address
+        })
+    }
+}
+
+/// Held for as long as the listener is connected.
+pub struct Permit {
+    counts: Arc<Mutex<Counts>>,
+    address: Option<IpAddr>
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock();
+        counts.total -= 1;
+
+        if let Some(address) = self.address {
+            if let Some(count) = counts.addresses.get_mut(&address) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.addresses.remove(&address);
+                }
+            }
+        }
+    }
+}
+
+impl Rejection {
+
+    pub fn status(&self) -> Status {
+        match self {
+            Rejection::Denied => Status::Forbidden,
+            Rejection::Full => Status::ServiceUnavailable,
+            Rejection::TooMany => Status::TooManyRequests
+        }
+    }
+
+    /// How long the client should wait before retrying, if there is any point in it.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Rejection::Denied => None,
+            Rejection::Full => Some(FULL_RETRY),
+            Rejection::TooMany => Some(TOO_MANY_RETRY)
+        }
+    }
+}
+
+impl<'r> response::Responder<'r, 'static> for Rejection {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = response::Response::build();
+        response.status(self.status());
+
+        if let Some(retry) = self.retry_after() {
+            response.header(Header::new("Retry-After", retry.as_secs().to_string()));
+        }
+
+        response.ok()
+    }
+}
+
+/// Request guard admitting the listener, the outcome is left to the route so that it can respond properly.
+pub struct Admission(pub Result<Permit, Rejection>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admission {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.rocket().state::<Limiter>() {
+            Some(limiter) => Outcome::Success(Admission(limiter.admit(req.client_ip()))),
+            None => Outcome::Failure((Status::InternalServerError, ()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(range: &str) -> Cidr {
+        range.parse().unwrap()
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_matches_by_prefix() {
+        let range = cidr("192.168.0.0/16");
+        assert!(range.contains(ip("192.168.1.2")));
+        assert!(!range.contains(ip("192.169.0.1")));
+        assert!(!range.contains(ip("fe80::1")));
+
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+
+        assert!(cidr("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert_eq!(cidr("10.0.0.1"), cidr("10.0.0.1/32"));
+
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+    }
+
+    #[test]
+    fn cidr_rejects_invalid_ranges() {
+        for range in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0.0/abc", "10.0.0/8", "example.com/8", ""] {
+            assert!(range.parse::<Cidr>().is_err(), "{}", range);
+        }
+    }
+
+    #[test]
+    fn cidr_matches_mapped_addresses_as_ipv4() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let limiter = Limiter::new(LimitOptions {
+            allow: vec![cidr("10.1.0.0/16")],
+            deny: vec![cidr("10.0.0.0/8")],
+            ..Default::default()
+        });
+
+        assert_eq!(limiter.admit(Some(ip("10.1.0.1"))).err(), Some(Rejection::Denied));
+        assert_eq!(limiter.admit(Some(ip("::ffff:10.2.0.1"))).err(), Some(Rejection::Denied));
+        assert!(limiter.admit(Some(ip("11.0.0.1"))).is_ok());
+        assert!(limiter.admit(None).is_ok());
+    }
+
+    #[test]
+    fn addresses_are_capped_unless_allowed() {
+        let limiter = Limiter::new(LimitOptions {
+            max_per_address: Some(2),
+            allow: vec![cidr("10.0.0.0/8")],
+            ..Default::default()
+        });
+
+        let first = limiter.admit(Some(ip("192.168.0.1"))).unwrap();
+        let _second = limiter.admit(Some(ip("192.168.0.1"))).unwrap();
+        assert_eq!(limiter.admit(Some(ip("192.168.0.1"))).err(), Some(Rejection::TooMany));
+        assert!(limiter.admit(Some(ip("192.168.0.2"))).is_ok());
+
+        // the slot is released along with the permit
+        drop(first);
+        assert!(limiter.admit(Some(ip("192.168.0.1"))).is_ok());
+
+        let relays: Vec<Permit> = (0..5).map(|_| limiter.admit(Some(ip("10.0.0.1"))).unwrap()).collect();
+        assert_eq!(relays.len(), 5);
+    }
+
+    #[test]
+    fn rejected_addresses_are_not_tracked() {
+        let limiter = Limiter::new(LimitOptions {
+            max_per_address: Some(0),
+            ..Default::default()
+        });
+
+        for last in 0..10 {
+            assert_eq!(limiter.admit(Some(IpAddr::from([192, 168, 0, last]))).err(), Some(Rejection::TooMany));
+        }
+
+        assert!(limiter.counts.lock().addresses.is_empty());
+    }
+
+    #[test]
+    fn listeners_are_capped_globally() {
+        let limiter = Limiter::new(LimitOptions {
+            max_listeners: Some(2),
+            allow: vec![cidr("10.0.0.0/8")],
+            ..Default::default()
+        });
+
+        let first = limiter.admit(Some(ip("192.168.0.1"))).unwrap();
+        let _second = limiter.admit(None).unwrap();
+
+        // the allowed ranges count too
+        assert_eq!(limiter.admit(Some(ip("10.0.0.1"))).err(), Some(Rejection::Full));
+
+        drop(first);
+        let _third = limiter.admit(Some(ip("10.0.0.1"))).unwrap();
+        assert_eq!(limiter.admit(Some(ip("192.168.0.2"))).err(), Some(Rejection::Full));
+        assert_eq!(limiter.counts.lock().total, 2);
+    }
+}
@@ -4,127 +4,440 @@
 extern crate rocket;
 
 mod audio;
+mod admin;
 pub mod broadcast;
 pub mod reader;
 pub mod schedule;
 pub mod static_files;
 pub mod events;
+pub mod websocket;
+pub mod station;
+pub mod metrics;
+pub mod limits;
+pub mod access;
 
 pub use audio::*;
-pub type EventStream = events::Join<Track, Listeners>;
+pub type EventStream = events::Join3<Track, Listeners, broadcast::EncoderChange>;
 
 #[get("/status")]
 fn rocket_status() -> String {
     "running".to_string()
 }
 
-#[get("/stream")]
-fn rocket_stream(broadcast: &rocket::State<broadcast::StreamManager>) -> broadcast::Stream {
-    broadcast.open()
+/// Where the client wants to start listening.
+#[derive(FromForm)]
+struct Position {
+    /// In milliseconds
+    burst: Option<u64>,
+    /// In seconds
+    offset: Option<u64>,
+    /// Unix time to start at, in seconds
+    at: Option<u64>
+}
+
+impl Position {
+
+    fn burst(&self) -> Option<std::time::Duration> {
+        self.burst.map(std::time::Duration::from_millis)
+    }
+
+    /// Timeshift requested by the client, either as an offset or as a time to start at.
+    fn offset(&self) -> std::time::Duration {
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        match (self.offset, self.at) {
+            (Some(offset), _) => Duration::from_secs(offset),
+            // a time in the future is live, one that can not be represented is too
+            (None, Some(at)) => UNIX_EPOCH.checked_add(Duration::from_secs(at))
+                .and_then(|at| SystemTime::now().duration_since(at).ok())
+                .unwrap_or_default(),
+            (None, None) => Duration::ZERO
+        }
+    }
+}
+
+/// Why a stream could not be opened.
+enum Refusal {
+    Limit(limits::Rejection),
+    Access(access::Denial),
+    Open(broadcast::OpenError)
+}
+
+impl From<limits::Rejection> for Refusal {
+    fn from(rejection: limits::Rejection) -> Self {
+        Refusal::Limit(rejection)
+    }
+}
+
+impl From<access::Denial> for Refusal {
+    fn from(denial: access::Denial) -> Self {
+        Refusal::Access(denial)
+    }
+}
+
+impl From<broadcast::OpenError> for Refusal {
+    fn from(e: broadcast::OpenError) -> Self {
+        Refusal::Open(e)
+    }
+}
+
+impl<'r> rocket::response::Responder<'r, 'static> for Refusal {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        match self {
+            Refusal::Limit(rejection) => rejection.respond_to(req),
+            Refusal::Access(denial) => denial.respond_to(req),
+            Refusal::Open(e) => e.respond_to(req)
+        }
+    }
+}
+
+type Listen = Result<broadcast::Stream, Refusal>;
+
+/// Opens the stream for an admitted listener, with a token if the station is private.
+/// The listener holds on to the permit until the stream ends.
+fn listen(admission: limits::Admission, credentials: access::Credentials, (id, station): (&str, &station::Station), tier: Option<&str>, position: Position) -> Listen {
+    let permit = admission.0?;
+
+    let stream = match station.private {
+        true => {
+            let grant = credentials.verify(id)?;
+            station.streams.open_as(&grant.holder, grant.max_listeners, tier, position.burst(), position.offset())?
+        },
+
+        false => station.streams.open_with(tier, position.burst(), position.offset())?
+    };
+
+    Ok(stream.hold(permit))
+}
+
+/// Lets everyone in to the public stations, and the token holders to the private ones.
+fn authorize(credentials: &access::Credentials, (id, station): (&str, &station::Station)) -> Result<(), access::Denial> {
+    if station.private {
+        credentials.verify(id)?;
+    }
+
+    Ok(())
+}
+
+/// Token the playlist of a private station passes on to its segments.
+fn playlist_token<'a>(credentials: &'a access::Credentials, station: &station::Station) -> Option<&'a str> {
+    credentials.token().filter(|_| station.private)
+}
+
+#[get("/stream?<quality>&<position..>")]
+fn rocket_stream(quality: Option<&str>, position: Position, admission: limits::Admission, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Listen {
+    listen(admission, credentials, (stations.default_id(), stations.default()), quality, position)
+}
+
+#[get("/stream/<quality>?<position..>")]
+fn rocket_stream_tier(quality: &str, position: Position, admission: limits::Admission, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Listen {
+    listen(admission, credentials, (stations.default_id(), stations.default()), Some(quality), position)
+}
+
+#[get("/stream.mp3?<position..>")]
+fn rocket_stream_mp3(position: Position, admission: limits::Admission, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Listen {
+    listen(admission, credentials, (stations.default_id(), stations.default()), Some("mp3"), position)
+}
+
+#[get("/stream.webm?<position..>")]
+fn rocket_stream_webm(position: Position, admission: limits::Admission, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Listen {
+    listen(admission, credentials, (stations.default_id(), stations.default()), Some("webm"), position)
+}
+
+#[get("/stream.flac?<position..>")]
+fn rocket_stream_flac(position: Position, admission: limits::Admission, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Listen {
+    listen(admission, credentials, (stations.default_id(), stations.default()), Some("flac"), position)
+}
+
+#[get("/hls/playlist.m3u8")]
+fn rocket_hls_playlist(credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Option<Result<broadcast::Resource, access::Denial>> {
+    let playlist = stations.default().playlist.as_ref()?;
+    Some(authorize(&credentials, (stations.default_id(), stations.default())).map(|_| playlist.playlist(playlist_token(&credentials, stations.default()))))
+}
+
+#[get("/hls/init.mp4")]
+fn rocket_hls_init(credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Option<Result<broadcast::Resource, access::Denial>> {
+    let playlist = stations.default().playlist.as_ref()?;
+    Some(authorize(&credentials, (stations.default_id(), stations.default())).map(|_| playlist.init()))
+}
+
+#[get("/hls/<segment>")]
+fn rocket_hls_segment(segment: &str, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Option<Result<broadcast::Resource, access::Denial>> {
+    let playlist = stations.default().playlist.as_ref()?;
+    if let Err(denial) = authorize(&credentials, (stations.default_id(), stations.default())) {
+        return Some(Err(denial));
+    }
+
+    playlist.segment(segment.strip_suffix(".m4s")?.parse().ok()?).map(Ok)
+}
+
+#[post("/admin/encoders/<tier>", data = "<settings>")]
+async fn rocket_admin_encoder(
+    tier: &str,
+    settings: rocket::serde::json::Json<broadcast::EncoderSettings>,
+    stations: &rocket::State<station::Stations>,
+    _admin: admin::Admin
+) -> Result<rocket::serde::json::Json<broadcast::EncoderSettings>, (rocket::http::Status, String)> {
+    stations.default().streams.configure(tier, settings.into_inner()).await
+        .map(rocket::serde::json::Json)
+        .map_err(|e| (rocket::http::Status::BadRequest, e.to_string()))
+}
+
+#[post("/admin/tokens", data = "<request>")]
+fn rocket_admin_mint(
+    request: rocket::serde::json::Json<access::MintRequest>,
+    access: &rocket::State<access::Access>,
+    stations: &rocket::State<station::Stations>,
+    _admin: admin::Admin
+) -> Result<rocket::serde::json::Json<access::MintResponse>, (rocket::http::Status, String)> {
+    if let Some(id) = &request.station {
+        if stations.get(id).is_none() {
+            return Err((rocket::http::Status::NotFound, format!("no such station: {}", id)));
+        }
+    }
+
+    let claims = request.claims().map_err(|e| (rocket::http::Status::BadRequest, e.to_string()))?;
+    access.mint(&claims)
+        .map(|token| rocket::serde::json::Json(access::MintResponse { token, expires: claims.exp }))
+        .map_err(|e| (rocket::http::Status::BadRequest, e.to_string()))
+}
+
+#[get("/admin/errors?<station>")]
+fn rocket_admin_errors(
+    station: Option<&str>,
+    stations: &rocket::State<station::Stations>,
+    _admin: admin::Admin
+) -> Option<rocket::serde::json::Json<Vec<broadcast::Failure>>> {
+    let station = match station {
+        Some(id) => stations.get(id)?,
+        None => stations.default()
+    };
+
+    Some(rocket::serde::json::Json(station.streams.errors()))
+}
+
+/// Listening statistics, with the sessions only for the admins.
+fn stats(access: admin::StatsAccess, station: &station::Station) -> rocket::serde::json::Json<broadcast::SessionReport> {
+    let report = station.streams.sessions().report();
+
+    rocket::serde::json::Json(match access {
+        admin::StatsAccess::Full => report,
+        admin::StatsAccess::Aggregate => report.aggregate()
+    })
+}
+
+#[get("/stats")]
+fn rocket_stats(stations: &rocket::State<station::Stations>, access: admin::StatsAccess) -> rocket::serde::json::Json<broadcast::SessionReport> {
+    stats(access, stations.default())
+}
+
+// only aggregates, so they are as public as the aggregate statistics
+#[get("/metrics")]
+fn rocket_metrics(stations: &rocket::State<station::Stations>, _access: admin::StatsAccess) -> String {
+    let mut exposition = metrics::Exposition::default();
+    exposition.process();
+
+    for (id, station) in stations.iter() {
+        station.streams.metrics(id, &mut exposition);
+    }
+
+    exposition.render()
 }
 
 #[get("/events")]
-fn rocket_events(events: &rocket::State<EventStream>) -> EventStream {
-    (*events).clone()
+fn rocket_events(credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Result<EventStream, access::Denial> {
+    authorize(&credentials, (stations.default_id(), stations.default()))?;
+    Ok(stations.default().events.clone())
+}
+
+#[get("/stations")]
+fn rocket_stations(stations: &rocket::State<station::Stations>) -> rocket::serde::json::Json<Vec<station::StationSummary>> {
+    rocket::serde::json::Json(stations.summary())
+}
+
+#[get("/stations/<id>/stream?<quality>&<position..>")]
+fn rocket_station_stream(id: &str, quality: Option<&str>, position: Position, admission: limits::Admission, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Option<Listen> {
+    Some(listen(admission, credentials, (id, stations.get(id)?), quality, position))
+}
+
+#[get("/stations/<id>/stream/<quality>?<position..>")]
+fn rocket_station_stream_tier(id: &str, quality: &str, position: Position, admission: limits::Admission, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Option<Listen> {
+    Some(listen(admission, credentials, (id, stations.get(id)?), Some(quality), position))
+}
+
+#[get("/stations/<id>/hls/playlist.m3u8")]
+fn rocket_station_hls_playlist(id: &str, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Option<Result<broadcast::Resource, access::Denial>> {
+    let station = stations.get(id)?;
+    let playlist = station.playlist.as_ref()?;
+    Some(authorize(&credentials, (id, station)).map(|_| playlist.playlist(playlist_token(&credentials, station))))
+}
+
+#[get("/stations/<id>/hls/init.mp4")]
+fn rocket_station_hls_init(id: &str, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Option<Result<broadcast::Resource, access::Denial>> {
+    let station = stations.get(id)?;
+    let playlist = station.playlist.as_ref()?;
+    Some(authorize(&credentials, (id, station)).map(|_| playlist.init()))
+}
+
+#[get("/stations/<id>/hls/<segment>")]
+fn rocket_station_hls_segment(id: &str, segment: &str, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Option<Result<broadcast::Resource, access::Denial>> {
+    let station = stations.get(id)?;
+    let playlist = station.playlist.as_ref()?;
+    if let Err(denial) = authorize(&credentials, (id, station)) {
+        return Some(Err(denial));
+    }
+
+    playlist.segment(segment.strip_suffix(".m4s")?.parse().ok()?).map(Ok)
+}
+
+#[get("/stations/<id>/stats")]
+fn rocket_station_stats(id: &str, stations: &rocket::State<station::Stations>, access: admin::StatsAccess) -> Option<rocket::serde::json::Json<broadcast::SessionReport>> {
+    Some(stats(access, stations.get(id)?))
+}
+
+#[get("/stations/<id>/events")]
+fn rocket_station_events(id: &str, credentials: access::Credentials, stations: &rocket::State<station::Stations>) -> Option<Result<EventStream, access::Denial>> {
+    let station = stations.get(id)?;
+    Some(authorize(&credentials, (id, station)).map(|_| station.events.clone()))
 }
 
 #[rocket::main]
 async fn main() -> Result<(), anyhow::Error> {
     let _ = dotenv::dotenv();
 
-    let tracks: Vec<Track> = reqwest::get(std::env::var("TRACKLIST_URL").expect("no TRACKLIST_URL set"))
-        .await?
-        .json()
-        .await?;
+    let config = station::Config::load()?;
+    let stations = station::Stations::start(&config).await?;
+    let streammgr = stations.default().streams.clone();
 
-    let mut schedule = schedule::requeue::Requeue::new(tracks);
-    schedule.shuffle();
+    let env = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string());
 
-    let format = audio::AudioFormat {
-        channels: 2,
-        sample_rate: 48000
-    };
+    let limiter = limits::Limiter::new(limits::LimitOptions::from_env()?);
 
-    let (mux_options, enc_options) = (reader::Options {
-        converter: reader::ConverterType::SincMediumQuality,
-        format,
-
-        buffer_size: 64 * 1024,
-        verify_decoding: true
-    }, broadcast::Options {
-        max_page: std::time::Duration::from_secs(1),
-        buffer_size: std::time::Duration::from_secs(7),
-        frame_size: broadcast::FrameSize::Ms60,
-        bit_rate: broadcast::Bitrate::Max,
-        signal: broadcast::Signal::Music,
-        bandwidth: broadcast::Bandwidth::Fullband,
-        application: broadcast::Application::Audio,
-        complexity: 5,
-        vbr: true
+    let access = access::Access::load()?;
+
+    let websocket_address = env("WEBSOCKET_ADDRESS", "0.0.0.0:8001").parse()?;
+    let websocket_stations = stations.clone();
+    let websocket_access = access.clone();
+    let websocket_limiter = limiter.clone();
+    tokio::spawn(async move {
+        if let Err(e) = websocket::run(websocket_address, websocket_stations, websocket_access, websocket_limiter).await {
+            eprintln!("websocket server error: {}", e);
+        }
     });
 
-    let (multiplexer, mux_handle) = reader::Multiplexer::new(format);
-    let streammgr = broadcast::run(multiplexer, enc_options).unwrap();
+    if let Ok(url) = std::env::var("ICECAST_URL") {
+        tokio::spawn(broadcast::relay(broadcast::IcecastOptions {
+            url: url.parse()?,
+            username: env("ICECAST_USERNAME", "source"),
+            password: env("ICECAST_PASSWORD", "hackme"),
+            tier: std::env::var("ICECAST_TIER").ok(),
+            public: env("ICECAST_PUBLIC", "0") == "1"
+        }, streammgr.clone()));
+    }
 
-    let (event_track, event_track_handle) = events::EventStream::new();
-    let (event_listeners, event_listeners_handle) = events::EventStream::new();
-    let events: EventStream = event_track.join(event_listeners);
+    let fade = std::time::Duration::from_secs(env("SHUTDOWN_FADE", "0").parse()?);
+    let deadline = std::time::Duration::from_secs(env("SHUTDOWN_DEADLINE", "10").parse()?);
 
-    tokio::spawn(run_control_thread(schedule, mux_options, mux_handle, event_track_handle));
-    tokio::spawn(run_listener_count_emitter_thread(streammgr.clone(), event_listeners_handle));
+    // the signals are handled here instead, so that the streams end properly before the server stops
+    let figment = rocket::Config::figment()
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.signals", Vec::<String>::new()));
 
-    rocket::build()
-        .manage(events)
-        .manage(streammgr)
+    let rocket = rocket::custom(figment)
+        .manage(stations.clone())
+        .manage(limiter)
+        .manage(access)
+        .manage(admin::AdminToken(std::env::var("ADMIN_TOKEN").ok()))
+        .manage(admin::PublicStats(env("STATS_PUBLIC", "0") == "1"))
         .mount("/", static_files::routes())
-        .mount("/", routes![rocket_stream, rocket_events, rocket_status])
-        .launch()
+        .mount("/", routes![
+            rocket_stream,
+            rocket_stream_tier,
+            rocket_stream_mp3,
+            rocket_stream_webm,
+            rocket_stream_flac,
+            rocket_hls_playlist,
+            rocket_hls_init,
+            rocket_hls_segment,
+            rocket_events,
+            rocket_stats,
+            rocket_metrics,
+            rocket_stations,
+            rocket_station_stream,
+            rocket_station_stream_tier,
+            rocket_station_hls_playlist,
+            rocket_station_hls_init,
+            rocket_station_hls_segment,
+            rocket_station_events,
+            rocket_station_stats,
+            rocket_admin_encoder,
+            rocket_admin_mint,
+            rocket_admin_errors,
+            rocket_status
+        ])
+        .ignite()
         .await?;
 
+    let server = rocket.shutdown();
+    tokio::spawn(async move {
+        terminated().await;
+        eprintln!("shutting down");
+
+        if tokio::time::timeout(deadline, stations.shutdown(fade)).await.is_err() {
+            eprintln!("the stations did not shut down in time");
+        }
+
+        server.notify();
+    });
+
+    rocket.launch().await?;
+
     Ok(())
 }
 
-async fn run_control_thread(
-    mut schedule: impl schedule::Schedule,
-    options: reader::Options,
-
-    mut handle: reader::Handle,
-    mut events: events::EventHandle<Track>
-) {
-    loop {
-        let track = schedule.next().await;
-        let stream = match reader::RemoteSource::new(&options, &track.audio_url).await {
-            Ok(x) => x,
-            Err(e) => {
-                eprintln!("failed to open the track at {}: {}", track.audio_url, e);
-                continue;
-            }
-        };
-
-        handle.send(Some(Box::new(stream))).await;
-        events.send(track);
-
-        if !handle.wait().await {
-            break;
+/// Resolves on SIGTERM or ctrl-c.
+async fn terminated() {
+    let mut term = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(e) => {
+            eprintln!("failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
         }
+    };
+
+    tokio::select! {
+        _ = term.recv() => {},
+        _ = tokio::signal::ctrl_c() => {}
     }
 }
 
-async fn run_listener_count_emitter_thread(
-    stream: broadcast::StreamManager,
-    mut events: events::EventHandle<Listeners>
-) {
-    loop {
-        let listeners = stream.count();
-        let update = match events.current() {
-            Some(data) => data.listeners != listeners,
-            None => true
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-        if update {
-            events.send(Listeners { listeners });
-        }
+    fn at(at: u64) -> Duration {
+        Position { burst: None, offset: None, at: Some(at) }.offset()
+    }
+
+    #[test]
+    fn position_at_is_an_offset_into_the_past() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        let offset = at(now - 60);
+        assert!(offset >= Duration::from_secs(60) && offset < Duration::from_secs(62), "{:?}", offset);
+        assert!(at(0) > Duration::from_secs(now - 1));
+
+        // the offset takes precedence
+        assert_eq!(Position { burst: None, offset: Some(5), at: Some(0) }.offset(), Duration::from_secs(5));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn position_at_out_of_range_is_live() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        assert_eq!(at(now + 3600), Duration::ZERO);
+        assert_eq!(at(u64::MAX), Duration::ZERO);
+    }
+}
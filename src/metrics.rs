@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+
+/// Blocks filled with silence since there was no track to play.
+pub static DECODER_UNDERRUNS: Counter = Counter::new();
+pub static TRACK_LOAD_FAILURES: Counter = Counter::new();
+pub static TRACK_LOAD_SECONDS: Histogram = Histogram::new(&[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]);
+pub static SSE_SUBSCRIBERS: Gauge = Gauge::new();
+
+/// Encoding time buckets, for a single block.
+pub const ENCODE_BUCKETS: &[f64] = &[0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025];
+
+/// Largest number of buckets a histogram can have.
+const MAX_BUCKETS: usize = 12;
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+
+    pub const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    /// Increments the gauge until the returned guard is dropped.
+    pub fn track(&'static self) -> GaugeGuard {
+        self.0.fetch_add(1, Relaxed);
+        GaugeGuard(self)
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Relaxed)
+    }
+}
+
+pub struct GaugeGuard(&'static Gauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.0.fetch_sub(1, Relaxed);
+    }
+}
+
+/// Histogram of durations, in seconds.
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: [AtomicU64; MAX_BUCKETS],
+    count: AtomicU64,
+    // in nanoseconds
+    sum: AtomicU64
+}
+
+impl Histogram {
+
+    pub const fn new(bounds: &'static [f64]) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        assert!(bounds.len() <= MAX_BUCKETS);
+
+        Self {
+            bounds,
+            buckets: [ZERO; MAX_BUCKETS],
+            count: ZERO,
+            sum: ZERO
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Relaxed);
+        }
+
+        self.count.fetch_add(1, Relaxed);
+        self.sum.fetch_add(duration.as_nanos() as u64, Relaxed);
+    }
+}
+
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    samples: String
+}
+
+/// Metrics in the Prometheus text format, grouped by name.
+#[derive(Default)]
+pub struct Exposition(BTreeMap<&'static str, Family>);
+
+impl Exposition {
+
+    pub fn counter(&mut self, name: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+        let samples = self.family(name, help, "counter");
+        sample(samples, name, "", labels, None, value);
+    }
+
+    pub fn gauge(&mut self, name: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+        let samples = self.family(name, help, "gauge");
+        sample(samples, name, "", labels, None, value);
+    }
+
+    pub fn histogram(&mut self, name: &'static str, help: &'static str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let samples = self.family(name, help, "histogram");
+
+        // the buckets are cumulative
+        let mut cumulative = 0;
+        for (bound, bucket) in histogram.bounds.iter().zip(histogram.buckets.iter()) {
+            cumulative += bucket.load(Relaxed);
+            sample(samples, name, "_bucket", labels, Some(&bound.to_string()), cumulative as f64);
+        }
+
+        let count = histogram.count.load(Relaxed);
+        sample(samples, name, "_bucket", labels, Some("+Inf"), count as f64);
+        sample(samples, name, "_sum", labels, None, histogram.sum.load(Relaxed) as f64 / 1e9);
+        sample(samples, name, "_count", labels, None, count as f64);
+    }
+
+    /// Adds the process wide metrics.
+    pub fn process(&mut self) {
+        self.counter("quartz_decoder_underruns_total", "Blocks filled with silence for lack of a track", &[], DECODER_UNDERRUNS.get() as f64);
+        self.counter("quartz_track_load_failures_total", "Tracks that failed to load", &[], TRACK_LOAD_FAILURES.get() as f64);
+        self.histogram("quartz_track_load_seconds", "Time to fetch and open a track", &[], &TRACK_LOAD_SECONDS);
+        self.gauge("quartz_sse_subscribers", "Connected event stream subscribers", &[], SSE_SUBSCRIBERS.get() as f64);
+    }
+
+    fn family(&mut self, name: &'static str, help: &'static str, kind: &'static str) -> &mut String {
+        &mut self.0.entry(name)
+            .or_insert_with(|| Family { help, kind, samples: String::new() })
+            .samples
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        for (name, family) in self.0.iter() {
+            let _ = writeln!(text, "# HELP {} {}", name, family.help);
+            let _ = writeln!(text, "# TYPE {} {}", name, family.kind);
+            text.push_str(&family.samples);
+        }
+
+        text
+    }
+}
+
+fn sample(samples: &mut String, name: &str, suffix: &str, labels: &[(&str, &str)], le: Option<&str>, value: f64) {
+    let mut pairs: Vec<String> = labels.iter()
+        .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+        .collect();
+
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    let _ = match pairs.is_empty() {
+        true => writeln!(samples, "{}{} {}", name, suffix, value),
+        false => writeln!(samples, "{}{}{{{}}} {}", name, suffix, pairs.join(","), value)
+    };
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_renders_the_text_format() {
+        static LATENCY: Histogram = Histogram::new(&[0.1, 1.0]);
+        for milliseconds in [50, 500, 2000].iter() {
+            LATENCY.observe(Duration::from_millis(*milliseconds));
+        }
+
+        let mut exposition = Exposition::default();
+        exposition.histogram("latency_seconds", "Latency", &[("station", "main")], &LATENCY);
+        exposition.counter("pages_total", "Pages sent", &[("station", "a\"b\\c\nd")], 3.0);
+        exposition.gauge("listeners", "Connected listeners", &[], 2.0);
+        exposition.counter("pages_total", "Pages sent", &[("station", "main")], 5.0);
+
+        assert_eq!(exposition.render(), concat!(
+            "# HELP latency_seconds Latency\n",
+            "# TYPE latency_seconds histogram\n",
+            "latency_seconds_bucket{station=\"main\",le=\"0.1\"} 1\n",
+            "latency_seconds_bucket{station=\"main\",le=\"1\"} 2\n",
+            "latency_seconds_bucket{station=\"main\",le=\"+Inf\"} 3\n",
+            "latency_seconds_sum{station=\"main\"} 2.55\n",
+            "latency_seconds_count{station=\"main\"} 3\n",
+            "# HELP listeners Connected listeners\n",
+            "# TYPE listeners gauge\n",
+            "listeners 2\n",
+            "# HELP pages_total Pages sent\n",
+            "# TYPE pages_total counter\n",
+            "pages_total{station=\"a\\\"b\\\\c\\nd\"} 3\n",
+            "pages_total{station=\"main\"} 5\n"
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{AudioSource, AudioFormat};
+use crate::{metrics, AudioSource, AudioFormat};
 use tokio::sync::mpsc::{Sender, Receiver, UnboundedReceiver, UnboundedSender, channel, unbounded_channel};
 
 pub type ConverterType = samplerate::ConverterType;
@@ -90,6 +90,7 @@ impl AudioSource for Multiplexer {
                 },
 
                 None => {
+                    metrics::DECODER_UNDERRUNS.inc();
                     std::thread::yield_now();
                     return Ok(0);
                 }
@@ -1,6 +1,7 @@
 use std::io::Cursor;
 use reqwest::Client;
-use crate::{AudioSource, AudioFormat};
+use std::time::Instant;
+use crate::{metrics, AudioSource, AudioFormat};
 use super::decoder::{AudioDecoder, Options as DecoderOptions};
 use super::Options;
 
@@ -11,6 +12,18 @@ pub struct RemoteSource {
 impl RemoteSource {
 
     pub async fn new(options: &Options, url: &str) -> anyhow::Result<Self> {
+        let started = Instant::now();
+        let result = Self::load(options, url).await;
+
+        metrics::TRACK_LOAD_SECONDS.observe(started.elapsed());
+        if result.is_err() {
+            metrics::TRACK_LOAD_FAILURES.inc();
+        }
+
+        result
+    }
+
+    async fn load(options: &Options, url: &str) -> anyhow::Result<Self> {
         let bytes= Client::builder().build()?
                 .get(url)
                 .header("Quartz-Radio", std::env!("CARGO_PKG_VERSION"))
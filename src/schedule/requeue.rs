@@ -22,6 +22,25 @@ impl Requeue {
         }
     }
 
+    /// Tracks in the order they are going to be played, starting with the current one.
+    pub fn queue(&self) -> impl Iterator<Item = &Track> {
+        self.queue.iter()
+    }
+
+    /// Restores the order returned by `queue`, skipping the tracks that are no longer on the list.
+    /// The tracks missing from the order are queued after the rest.
+    pub fn restore(&mut self, order: impl IntoIterator<Item = Track>) {
+        let mut rest = std::mem::take(&mut self.queue);
+
+        for track in order {
+            if let Some(index) = rest.iter().position(|other| other.audio_url == track.audio_url) {
+                self.queue.extend(rest.remove(index));
+            }
+        }
+
+        self.queue.extend(rest);
+    }
+
     pub fn shift(&mut self) {
         let next = self.queue.pop_front().expect("queue should not be empty");
         let position = thread_rng().gen_range::<f32, _>(0.0..0.6);
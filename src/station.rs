@@ -0,0 +1,915 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use rocket::figment::Figment;
+use rocket::figment::providers::{Format, Toml};
+use serde::{Serialize, Deserialize};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::{broadcast, events, reader, schedule, AudioFormat, EventStream, Listeners, Track};
+use crate::schedule::Schedule;
+
+/// Stations run by the process, usually read from a TOML file:
+///
+/// ```toml
+/// default = "main"
+///
+/// [stations.main]
+/// name = "Quartz Radio"
+/// tracklist = "https://example.com/tracks.json"
+///
+/// [[stations.main.tiers]]
+/// name = "high"
+/// codec = "opus"
+/// bit_rate = 192
+/// complexity = 10
+///
+/// [stations.main.recording]
+/// rotation = "track"
+/// retention_days = 90
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+
+    /// Station served at the top level routes, the first one if not set
+    #[serde(default)]
+    pub default: Option<String>,
+
+    pub stations: BTreeMap<String, StationConfig>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StationConfig {
+    pub name: String,
+
+    #[serde(default)]
+    pub genre: String,
+
+    #[serde(default)]
+    pub description: String,
+
+    #[serde(default)]
+    pub url: String,
+
+    /// URL of the JSON track list
+    pub tracklist: String,
+
+    /// Whether the listeners need a token
+    #[serde(default)]
+    pub private: bool,
+
+    /// Gain in dB, applied losslessly by the decoders via the stream headers
+    #[serde(default)]
+    pub output_gain: f32,
+
+    /// The first tier is the default one, a single Opus tier if not set
+    #[serde(default = "default_tiers")]
+    pub tiers: Vec<TierConfig>,
+
+    /// Whether to serve an HLS playlist as well
+    #[serde(default)]
+    pub hls: bool,
+
+    /// How the station is recorded, if `RECORDING_DIRECTORY` is set
+    #[serde(default)]
+    pub recording: RecordingConfig
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct RecordingConfig {
+    /// Tier that is recorded, the default one if not set
+    #[serde(default)]
+    pub tier: Option<String>,
+
+    /// `track` for a file per track (or show), otherwise the length of the files in minutes, 60 if not set
+    #[serde(default)]
+    pub rotation: Option<String>,
+
+    /// Days the recordings are kept for, 30 if not set
+    #[serde(default)]
+    pub retention_days: Option<u64>
+}
+
+impl RecordingConfig {
+
+    fn rotation(&self) -> anyhow::Result<broadcast::Rotation> {
+        match self.rotation.as_deref() {
+            None => Ok(broadcast::Rotation::Every(Duration::from_secs(60 * 60))),
+            Some("track") => Ok(broadcast::Rotation::Track),
+            Some(minutes) => match minutes.parse::<u64>() {
+                Ok(minutes) if minutes > 0 => Ok(broadcast::Rotation::Every(Duration::from_secs(minutes.saturating_mul(60)))),
+                _ => Err(anyhow::Error::msg(format!("recording rotation must be `track` or a number of minutes: {}", minutes)))
+            }
+        }
+    }
+
+    fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention_days.unwrap_or(30).saturating_mul(24 * 60 * 60))
+    }
+}
+
+/// Encoder profile of a tier. The options left out keep their defaults,
+/// the ones that do not apply to the codec are ignored.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TierConfig {
+    pub name: String,
+    pub codec: CodecKind,
+
+    /// Bitrate in kbps, the highest one for Opus and 128 for MP3 if not set
+    #[serde(default)]
+    pub bit_rate: Option<u32>,
+
+    /// Amount of audio sent to the new listeners right away, in milliseconds, 7000 if not set
+    #[serde(default)]
+    pub buffer: Option<u64>,
+
+    /// Longest page, in milliseconds, 1000 if not set
+    #[serde(default)]
+    pub max_page: Option<u64>,
+
+    /// Opus frame length in milliseconds: 2.5, 5, 10, 20, 40 or 60 (the default)
+    #[serde(default)]
+    pub frame_size: Option<f32>,
+
+    /// Opus complexity, 0 (fastest) to 10 (best), 5 if not set
+    #[serde(default)]
+    pub complexity: Option<u8>,
+
+    /// Opus signal type: `auto`, `voice` or `music` (the default)
+    #[serde(default)]
+    pub signal: Option<String>,
+
+    /// Opus bandwidth: `auto`, `narrowband`, `mediumband`, `wideband`, `superwideband` or `fullband` (the default)
+    #[serde(default)]
+    pub bandwidth: Option<String>,
+
+    /// Opus variable bitrate, on if not set
+    #[serde(default)]
+    pub vbr: Option<bool>,
+
+    /// Keeps the Opus bitrate close to the target in VBR mode
+    #[serde(default)]
+    pub vbr_constraint: bool,
+
+    /// Opus in-band forward error correction, for the `packet_loss` expected
+    #[serde(default)]
+    pub fec: bool,
+
+    /// Expected packet loss in percent, 0 if not set
+    #[serde(default)]
+    pub packet_loss: Option<u8>,
+
+    /// Opus discontinuous transmission
+    #[serde(default)]
+    pub dtx: bool,
+
+    /// Depth of the input signal in bits, 8 to 24 (the default)
+    #[serde(default)]
+    pub lsb_depth: Option<u8>,
+
+    /// Opus channel coding: `auto` (the default), `mono` or `stereo`
+    #[serde(default)]
+    pub force_channels: Option<String>,
+
+    /// Disables the Opus inter-frame prediction, making every frame independently decodable
+    #[serde(default)]
+    pub prediction_disabled: bool,
+
+    /// LAME quality, 0 (best) to 9 (fastest), 5 if not set
+    #[serde(default)]
+    pub quality: Option<u8>,
+
+    /// FLAC samples per channel in a frame, 4096 if not set
+    #[serde(default)]
+    pub block_size: Option<u16>
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Debug, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecKind {
+    Opus,
+    Webm,
+    Fmp4,
+    Packets,
+    Mp3,
+    Flac
+}
+
+impl TierConfig {
+
+    fn new(name: &str, codec: CodecKind, bit_rate: Option<u32>, buffer: Option<u64>) -> Self {
+        Self {
+            name: name.to_string(),
+            codec,
+            bit_rate,
+            buffer,
+            max_page: None,
+            frame_size: None,
+            complexity: None,
+            signal: None,
+            bandwidth: None,
+            vbr: None,
+            vbr_constraint: false,
+            fec: false,
+            packet_loss: None,
+            dtx: false,
+            lsb_depth: None,
+            force_channels: None,
+            prediction_disabled: false,
+            quality: None,
+            block_size: None
+        }
+    }
+
+    /// Builds the codec on top of the station's Opus options.
+    fn codec(&self, opus: &broadcast::Options, format: AudioFormat) -> anyhow::Result<broadcast::Codec> {
+        let buffer_size = self.buffer.map_or(opus.buffer_size, Duration::from_millis);
+        let max_page = self.max_page.map_or(opus.max_page, Duration::from_millis);
+
+        if self.complexity.is_some_and(|complexity| complexity > 10) {
+            return Err(anyhow::Error::msg(format!("complexity of tier {} must be between 0 and 10", self.name)));
+        }
+
+        if self.packet_loss.is_some_and(|packet_loss| packet_loss > 100) {
+            return Err(anyhow::Error::msg(format!("packet loss of tier {} must be between 0 and 100", self.name)));
+        }
+
+        // the blocks of a cluster are timed with a signed 16 bit offset in milliseconds
+        if matches!(self.codec, CodecKind::Webm) && max_page > Duration::from_millis(i16::MAX as u64) {
+            return Err(anyhow::Error::msg(format!("max page of WebM tier {} must be at most {}ms", self.name, i16::MAX)));
+        }
+
+        let bit_rate = match self.bit_rate {
+            Some(bit_rate) => match bit_rate.checked_mul(1000).filter(|bits| *bits <= i32::MAX as u32) {
+                Some(bits) => broadcast::Bitrate::BitsPerSecond(bits as i32),
+                None => return Err(anyhow::Error::msg(format!("bit rate of tier {} is out of range", self.name)))
+            },
+            None => opus.bit_rate
+        };
+
+        let options = broadcast::Options {
+            frame_size: match self.frame_size {
+                Some(frame_size) => parse_frame_size(frame_size)?,
+                None => opus.frame_size
+            },
+            bit_rate,
+            signal: self.signal.as_deref().map_or(Ok(opus.signal), broadcast::parse_signal)?,
+            bandwidth: self.bandwidth.as_deref().map_or(Ok(opus.bandwidth), broadcast::parse_bandwidth)?,
+            complexity: self.complexity.unwrap_or(opus.complexity),
+            vbr: self.vbr.unwrap_or(opus.vbr),
+            vbr_constraint: self.vbr_constraint,
+            fec: self.fec,
+            packet_loss: self.packet_loss.unwrap_or(opus.packet_loss),
+            dtx: self.dtx,
+            lsb_depth: self.lsb_depth.unwrap_or(opus.lsb_depth),
+            force_channels: self.force_channels.as_deref().map_or(Ok(opus.force_channels), broadcast::parse_channels)?,
+            prediction_disabled: self.prediction_disabled,
+            max_page,
+            buffer_size,
+            ..opus.clone()
+        };
+
+        if matches!(self.codec, CodecKind::Opus | CodecKind::Webm | CodecKind::Fmp4 | CodecKind::Packets) {
+            broadcast::EncoderSettings::from_options(&options).validate(format)
+                .map_err(|e| anyhow::Error::msg(format!("tier {}: {}", self.name, e)))?;
+        }
+
+        Ok(match self.codec {
+            CodecKind::Opus => broadcast::Codec::Opus(options),
+            CodecKind::Webm => broadcast::Codec::WebM(options),
+            CodecKind::Fmp4 => broadcast::Codec::Fmp4(options),
+            CodecKind::Packets => broadcast::Codec::Packets(options),
+            CodecKind::Mp3 => broadcast::Codec::Mp3(broadcast::Mp3Options {
+                bit_rate: self.bit_rate.unwrap_or(128),
+                quality: self.quality.unwrap_or(5).min(9),
+                max_page,
+                buffer_size
+            }),
+
+            CodecKind::Flac => broadcast::Codec::Flac(broadcast::FlacOptions {
+                block_size: self.block_size.unwrap_or(4096),
+                max_page,
+                buffer_size
+            })
+        })
+    }
+}
+
+/// Converts a gain in dB to the Q7.8 value of the stream headers.
+fn q78_gain(gain: f32) -> anyhow::Result<i16> {
+    let q78 = (gain * 256.0).round();
+    if !(i16::MIN as f32..=i16::MAX as f32).contains(&q78) {
+        return Err(anyhow::Error::msg(format!("output gain of {}dB is out of range, it must be between -128 and 127", gain)));
+    }
+
+    Ok(q78 as i16)
+}
+
+fn parse_frame_size(milliseconds: f32) -> anyhow::Result<broadcast::FrameSize> {
+    Ok(match (milliseconds * 10.0).round() as u32 {
+        25 => broadcast::FrameSize::Ms2Half,
+        50 => broadcast::FrameSize::Ms5,
+        100 => broadcast::FrameSize::Ms10,
+        200 => broadcast::FrameSize::Ms20,
+        400 => broadcast::FrameSize::Ms40,
+        600 => broadcast::FrameSize::Ms60,
+        _ => return Err(anyhow::Error::msg(format!("unsupported frame size: {}ms", milliseconds)))
+    })
+}
+
+fn default_tiers() -> Vec<TierConfig> {
+    vec![TierConfig::new("opus", CodecKind::Opus, None, None)]
+}
+
+/// Tiers that can be picked by name with `STATION_TIERS`.
+fn preset_tier(name: &str) -> anyhow::Result<TierConfig> {
+    Ok(match name {
+        "opus" => TierConfig::new(name, CodecKind::Opus, None, None),
+        "high" => TierConfig::new(name, CodecKind::Opus, Some(192), None),
+        "medium" => TierConfig::new(name, CodecKind::Opus, Some(96), None),
+        "low" => TierConfig::new(name, CodecKind::Opus, Some(32), None),
+        "webm" => TierConfig::new(name, CodecKind::Webm, Some(192), None),
+        "fmp4" => TierConfig::new(name, CodecKind::Fmp4, Some(192), None),
+        "packets" => TierConfig::new(name, CodecKind::Packets, Some(128), Some(500)),
+        "flac" => TierConfig::new(name, CodecKind::Flac, None, None),
+        "mp3" => TierConfig::new(name, CodecKind::Mp3, Some(128), None),
+        _ => return Err(anyhow::Error::msg(format!("unknown tier: {}", name)))
+    })
+}
+
+impl Config {
+
+    /// Reads the stations from the TOML file at `STATIONS_CONFIG`,
+    /// or a single `default` station from the environment if there is none.
+    pub fn load() -> anyhow::Result<Self> {
+        if let Ok(path) = std::env::var("STATIONS_CONFIG") {
+            let config: Config = Figment::from(Toml::file(path)).extract()?;
+            if config.stations.is_empty() {
+                return Err(anyhow::Error::msg("no stations configured"));
+            }
+
+            return Ok(config);
+        }
+
+        let env = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string());
+        let station = StationConfig {
+            name: env("STATION_NAME", "Quartz Radio"),
+            genre: env("STATION_GENRE", "Various"),
+            description: env("STATION_DESCRIPTION", "A primitive online radio"),
+            url: env("STATION_URL", "https://quartzmusic.herokuapp.com/"),
+            tracklist: std::env::var("TRACKLIST_URL").map_err(|_| anyhow::Error::msg("no TRACKLIST_URL set"))?,
+            private: env("STATION_PRIVATE", "0") == "1",
+            output_gain: std::env::var("OUTPUT_GAIN").ok().map(|gain| gain.parse()).transpose()?.unwrap_or(0.0),
+            tiers: match std::env::var("STATION_TIERS") {
+                Ok(names) => names.split(',').map(|name| preset_tier(name.trim())).collect::<anyhow::Result<_>>()?,
+                Err(_) => default_tiers()
+            },
+            hls: env("STATION_HLS", "0") == "1",
+            recording: RecordingConfig {
+                tier: std::env::var("RECORDING_TIER").ok(),
+                rotation: std::env::var("RECORDING_ROTATION").ok(),
+                retention_days: std::env::var("RECORDING_RETENTION_DAYS").ok().map(|days| days.parse()).transpose()?
+            }
+        };
+
+        Ok(Config {
+            default: None,
+            stations: BTreeMap::from([("default".to_string(), station)])
+        })
+    }
+}
+
+/// Running station.
+pub struct Station {
+    pub name: String,
+    pub private: bool,
+    pub streams: broadcast::StreamManager,
+    pub events: EventStream,
+    /// HLS playlist, if enabled
+    pub playlist: Option<broadcast::Playlist>,
+    stop: watch::Sender<bool>,
+    tasks: Mutex<Vec<JoinHandle<()>>>
+}
+
+impl Station {
+
+    /// Fetches the track list and starts broadcasting.
+    pub async fn start(id: &str, config: &StationConfig) -> anyhow::Result<Self> {
+        let output_gain = q78_gain(config.output_gain)?;
+        let tracks: Vec<Track> = reqwest::get(&config.tracklist)
+            .await?
+            .json()
+            .await?;
+
+        // the play order survives restarts if there is a place for it
+        let state = std::env::var("SCHEDULE_STATE_DIRECTORY").ok()
+            .map(|directory| PathBuf::from(directory).join(format!("{}.json", id)));
+
+        let mut schedule = schedule::requeue::Requeue::new(tracks);
+        match state.as_deref().and_then(load_order) {
+            Some(order) => schedule.restore(order),
+            None => schedule.shuffle()
+        }
+
+        let format = AudioFormat {
+            channels: 2,
+            sample_rate: 48000
+        };
+
+        let mux_options = reader::Options {
+            converter: reader::ConverterType::SincMediumQuality,
+            format,
+
+            buffer_size: 64 * 1024,
+            verify_decoding: true
+        };
+
+        let opus = broadcast::Options {
+            max_page: Duration::from_secs(1),
+            buffer_size: Duration::from_secs(7),
+            frame_size: broadcast::FrameSize::Ms60,
+            bit_rate: broadcast::Bitrate::Max,
+            signal: broadcast::Signal::Music,
+            bandwidth: broadcast::Bandwidth::Fullband,
+            application: broadcast::Application::Audio,
+            complexity: 5,
+            vbr: true,
+            vbr_constraint: false,
+            fec: false,
+            packet_loss: 0,
+            dtx: false,
+            lsb_depth: 24,
+            force_channels: broadcast::Channels::Auto,
+            prediction_disabled: false,
+            output_gain
+        };
+
+        // listeners can start up to half an hour in the past if there is a place for the history
+        let timeshift = std::env::var("TIMESHIFT_DIRECTORY").ok().map(|directory| broadcast::Timeshift {
+            length: Duration::from_secs(30 * 60),
+            memory: Duration::from_secs(60),
+            directory: PathBuf::from(directory).join(id)
+        });
+
+        let tiers = config.tiers.iter()
+            .map(|tier| {
+                let codec = tier.codec(&opus, format)?;
+
+                // slow listeners lose the oldest pages once they fall behind by twice the buffer
+                Ok(broadcast::Tier {
+                    name: tier.name.clone(),
+                    max_lag: codec.buffer_size() * 2,
+                    min_burst: Duration::ZERO,
+                    timeshift: timeshift.clone(),
+                    overflow: broadcast::Overflow::DropOldest,
+                    codec
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut sinks: Vec<Box<dyn broadcast::Sink>> = Vec::new();
+        let playlist = match config.hls {
+            true => {
+                let (segmenter, playlist) = broadcast::Segmenter::new(format, &broadcast::HlsOptions {
+                    codec: broadcast::Options {
+                        max_page: Duration::from_secs(6),
+                        bit_rate: broadcast::Bitrate::BitsPerSecond(128000),
+                        ..opus.clone()
+                    },
+                    window: 5
+                })?;
+
+                sinks.push(Box::new(segmenter));
+                Some(playlist)
+            },
+
+            false => None
+        };
+
+        let info = broadcast::StationInfo {
+            name: config.name.clone(),
+            genre: config.genre.clone(),
+            description: config.description.clone(),
+            url: config.url.clone()
+        };
+
+        let (event_track, event_track_handle) = events::EventStream::new();
+        let (event_listeners, event_listeners_handle) = events::EventStream::new();
+
+        let (multiplexer, mux_handle) = reader::Multiplexer::new(format);
+        // air-check for compliance, hourly files of the default tier unless configured otherwise
+        let recorder = match std::env::var("RECORDING_DIRECTORY") {
+            Ok(directory) => {
+                let tier = config.recording.tier.clone()
+                    .unwrap_or_else(|| tiers.first().map(|tier| tier.name.clone()).unwrap_or_default());
+
+                if !tiers.iter().any(|candidate| candidate.name == tier) {
+                    return Err(anyhow::Error::msg(format!("no such tier to record: {}", tier)));
+                }
+
+                Some(broadcast::RecorderOptions {
+                    directory: directory.into(),
+                    prefix: id.to_string(),
+                    tier,
+                    rotation: config.recording.rotation()?,
+                    retention: config.recording.retention()
+                })
+            },
+
+            Err(_) => None
+        };
+
+        let streams = broadcast::run(multiplexer, tiers, sinks, event_track.clone(), info)?;
+
+        // session statistics survive restarts if there is a place for them
+        let statistics = std::env::var("STATS_DIRECTORY").ok()
+            .map(|directory| PathBuf::from(directory).join(format!("{}.json", id)));
+
+        if let Some(path) = &statistics {
+            if let Err(e) = streams.sessions().load(path) {
+                eprintln!("failed to read the statistics at {}: {}", path.display(), e);
+            }
+        }
+        let events: EventStream = event_track.join(event_listeners).join(streams.changes());
+
+        let (stop, stopped) = watch::channel(false);
+        let mut tasks = vec![
+            tokio::spawn(run_control_thread(schedule, mux_options, mux_handle, event_track_handle, stopped.clone(), state)),
+            tokio::spawn(run_listener_count_emitter_thread(streams.clone(), event_listeners_handle, stopped, statistics))
+        ];
+
+        // ends along with the broadcast, once the last pages are written
+        if let Some(options) = recorder {
+            tasks.push(tokio::spawn(broadcast::record(options, streams.clone())));
+        }
+
+        Ok(Station {
+            name: config.name.clone(),
+            private: config.private,
+            streams,
+            events,
+            playlist,
+            stop,
+            tasks: Mutex::new(tasks)
+        })
+    }
+
+    /// Ends the streams after fading the audio out, then stops the schedule and closes the event streams.
+    pub async fn shutdown(&self, fade: Duration) {
+        self.streams.shutdown(fade).await;
+        let _ = self.stop.send(true);
+
+        let tasks = std::mem::take(&mut *self.tasks.lock());
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+/// All the running stations, by their id.
+#[derive(Clone)]
+pub struct Stations {
+    stations: Arc<BTreeMap<String, Station>>,
+    default: String
+}
+
+/// Station summary for the listing.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct StationSummary {
+    pub id: String,
+    pub name: String,
+    pub listeners: usize
+}
+
+impl Stations {
+
+    pub async fn start(config: &Config) -> anyhow::Result<Self> {
+        let default = match &config.default {
+            Some(id) if config.stations.contains_key(id) => id.clone(),
+            Some(id) => return Err(anyhow::Error::msg(format!("no such station: {}", id))),
+            None => config.stations.keys().next()
+                .ok_or_else(|| anyhow::Error::msg("no stations configured"))?
+                .clone()
+        };
+
+        let mut stations = BTreeMap::new();
+        for (id, station) in config.stations.iter() {
+            stations.insert(id.clone(), Station::start(id, station).await?);
+        }
+
+        Ok(Self { stations: Arc::new(stations), default })
+    }
+
+    /// Shuts all the stations down at once.
+    pub async fn shutdown(&self, fade: Duration) {
+        rocket::futures::future::join_all(self.stations.values().map(|station| station.shutdown(fade))).await;
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Station> {
+        self.stations.get(id)
+    }
+
+    /// Station served at the top level routes.
+    pub fn default(&self) -> &Station {
+        &self.stations[&self.default]
+    }
+
+    pub fn default_id(&self) -> &str {
+        &self.default
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Station)> {
+        self.stations.iter()
+    }
+
+    pub fn summary(&self) -> Vec<StationSummary> {
+        self.stations.iter()
+            .map(|(id, station)| StationSummary {
+                id: id.clone(),
+                name: station.name.clone(),
+                listeners: station.streams.count()
+            })
+            .collect()
+    }
+}
+
+/// Reads the play order saved on shutdown, if there is one.
+fn load_order(path: &Path) -> Option<Vec<Track>> {
+    let data = std::fs::read(path).ok()?;
+
+    match rocket::serde::json::serde_json::from_slice(&data) {
+        Ok(order) => Some(order),
+        Err(e) => {
+            eprintln!("failed to read the schedule state at {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn save_order(path: &Path, schedule: &schedule::requeue::Requeue) -> anyhow::Result<()> {
+    let order: Vec<&Track> = schedule.queue().collect();
+
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+
+    std::fs::write(path, rocket::serde::json::serde_json::to_vec(&order)?)?;
+    Ok(())
+}
+
+async fn run_control_thread(
+    mut schedule: schedule::requeue::Requeue,
+    options: reader::Options,
+
+    mut handle: reader::Handle,
+    mut events: events::EventHandle<Track>,
+    mut stop: watch::Receiver<bool>,
+    state: Option<PathBuf>
+) {
+    loop {
+        let track = tokio::select! {
+            track = schedule.next() => track,
+            _ = stop.changed() => break
+        };
+
+        let stream = tokio::select! {
+            stream = reader::RemoteSource::new(&options, &track.audio_url) => stream,
+            _ = stop.changed() => break
+        };
+
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("failed to open the track at {}: {}", track.audio_url, e);
+                continue;
+            }
+        };
+
+        handle.send(Some(Box::new(stream))).await;
+        events.send(track);
+
+        let playing = tokio::select! {
+            playing = handle.wait() => playing,
+            _ = stop.changed() => break
+        };
+
+        if !playing {
+            break;
+        }
+    }
+
+    if let Some(path) = state {
+        if let Err(e) = save_order(&path, &schedule) {
+            eprintln!("failed to save the schedule state to {}: {}", path.display(), e);
+        }
+    }
+}
+
+async fn run_listener_count_emitter_thread(
+    stream: broadcast::StreamManager,
+    mut events: events::EventHandle<Listeners>,
+    mut stop: watch::Receiver<bool>,
+    statistics: Option<PathBuf>
+) {
+    let save = |path: &Path| {
+        if let Err(e) = stream.sessions().save(path) {
+            eprintln!("failed to save the statistics to {}: {}", path.display(), e);
+        }
+    };
+
+    for tick in 0u64.. {
+        // the statistics are saved once a minute and on shutdown
+        if let Some(path) = &statistics {
+            if tick % 60 == 59 {
+                save(path);
+            }
+        }
+
+        let listeners = Listeners {
+            listeners: stream.count(),
+            tiers: stream.counts(),
+            lagging: stream.lag().values().map(|lag| lag.lagging).sum()
+        };
+
+        let update = match events.current() {
+            Some(data) => *data != listeners,
+            None => true
+        };
+
+        if update {
+            events.send(listeners);
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {},
+            _ = stop.changed() => break
+        }
+    }
+
+    if let Some(path) = &statistics {
+        save(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opus() -> broadcast::Options {
+        broadcast::Options {
+            max_page: Duration::from_secs(1),
+            buffer_size: Duration::from_secs(7),
+            frame_size: broadcast::FrameSize::Ms60,
+            bit_rate: broadcast::Bitrate::Max,
+            signal: broadcast::Signal::Music,
+            bandwidth: broadcast::Bandwidth::Fullband,
+            application: broadcast::Application::Audio,
+            complexity: 5,
+            vbr: true,
+            vbr_constraint: false,
+            fec: false,
+            packet_loss: 0,
+            dtx: false,
+            lsb_depth: 24,
+            force_channels: broadcast::Channels::Auto,
+            prediction_disabled: false,
+            output_gain: 0
+        }
+    }
+
+    const FORMAT: AudioFormat = AudioFormat {
+        channels: 2,
+        sample_rate: 48000
+    };
+
+    fn config(toml: &str) -> Config {
+        Figment::from(Toml::string(toml)).extract().unwrap()
+    }
+
+    #[test]
+    fn station_defaults_to_a_single_opus_tier() {
+        let config = config("[stations.main]\nname = \"Main\"\ntracklist = \"https://example.com/tracks.json\"");
+        let station = &config.stations["main"];
+
+        assert!(!station.hls);
+        assert_eq!(station.recording.rotation().unwrap(), broadcast::Rotation::Every(Duration::from_secs(60 * 60)));
+        assert_eq!(station.recording.retention(), Duration::from_secs(30 * 24 * 60 * 60));
+        assert_eq!(station.tiers, vec![TierConfig::new("opus", CodecKind::Opus, None, None)]);
+        assert_eq!(station.tiers[0].codec(&opus(), FORMAT).unwrap(), broadcast::Codec::Opus(opus()));
+    }
+
+    #[test]
+    fn tier_options_override_the_defaults() {
+        let config = config(r#"
+            [stations.main]
+            name = "Main"
+            tracklist = "https://example.com/tracks.json"
+            hls = true
+
+            [[stations.main.tiers]]
+            name = "voice"
+            codec = "opus"
+            bit_rate = 24
+            frame_size = 2.5
+            complexity = 10
+            signal = "voice"
+            bandwidth = "wideband"
+            fec = true
+            packet_loss = 10
+            vbr_constraint = true
+            lsb_depth = 16
+            force_channels = "mono"
+            prediction_disabled = true
+
+            [[stations.main.tiers]]
+            name = "flac"
+            codec = "flac"
+            block_size = 1152
+            buffer = 3000
+
+            [stations.main.recording]
+            tier = "flac"
+            rotation = "track"
+            retention_days = 7
+        "#);
+
+        let station = &config.stations["main"];
+        assert!(station.hls);
+        assert_eq!(station.recording.tier.as_deref(), Some("flac"));
+        assert_eq!(station.recording.rotation().unwrap(), broadcast::Rotation::Track);
+        assert_eq!(station.recording.retention(), Duration::from_secs(7 * 24 * 60 * 60));
+
+        let expected = broadcast::Options {
+            bit_rate: broadcast::Bitrate::BitsPerSecond(24000),
+            frame_size: broadcast::FrameSize::Ms2Half,
+            complexity: 10,
+            signal: broadcast::Signal::Voice,
+            bandwidth: broadcast::Bandwidth::Wideband,
+            fec: true,
+            packet_loss: 10,
+            vbr_constraint: true,
+            lsb_depth: 16,
+            force_channels: broadcast::Channels::Mono,
+            prediction_disabled: true,
+            ..opus()
+        };
+        assert_eq!(station.tiers[0].codec(&opus(), FORMAT).unwrap(), broadcast::Codec::Opus(expected));
+
+        assert_eq!(station.tiers[1].codec(&opus(), FORMAT).unwrap(), broadcast::Codec::Flac(broadcast::FlacOptions {
+            block_size: 1152,
+            max_page: Duration::from_secs(1),
+            buffer_size: Duration::from_secs(3)
+        }));
+    }
+
+    #[test]
+    fn invalid_tier_options_are_rejected() {
+        let tier = |change: fn(&mut TierConfig)| {
+            let mut tier = TierConfig::new("opus", CodecKind::Opus, None, None);
+            change(&mut tier);
+            tier.codec(&opus(), FORMAT)
+        };
+
+        let mono = |change: fn(&mut TierConfig)| {
+            let mut tier = TierConfig::new("opus", CodecKind::Opus, None, None);
+            change(&mut tier);
+            tier.codec(&opus(), AudioFormat { channels: 1, ..FORMAT })
+        };
+
+        assert!(tier(|tier| tier.frame_size = Some(30.0)).is_err());
+        assert!(tier(|tier| tier.complexity = Some(11)).is_err());
+        assert!(tier(|tier| tier.packet_loss = Some(101)).is_err());
+        assert!(tier(|tier| tier.bit_rate = Some(513)).is_err());
+        assert!(tier(|tier| tier.bit_rate = Some(u32::MAX)).is_err());
+        assert!(tier(|tier| tier.signal = Some("speech".to_string())).is_err());
+        assert!(tier(|tier| tier.bandwidth = Some("ultraband".to_string())).is_err());
+        assert!(tier(|tier| tier.lsb_depth = Some(7)).is_err());
+        assert!(tier(|tier| tier.lsb_depth = Some(25)).is_err());
+        assert!(tier(|tier| tier.force_channels = Some("surround".to_string())).is_err());
+        assert!(mono(|tier| tier.force_channels = Some("stereo".to_string())).is_err());
+        assert!(mono(|tier| tier.force_channels = Some("mono".to_string())).is_ok());
+        assert!(preset_tier("ultra").is_err());
+
+        let webm = |max_page: u64| {
+            let mut tier = TierConfig::new("webm", CodecKind::Webm, None, None);
+            tier.max_page = Some(max_page);
+            tier.codec(&opus(), FORMAT)
+        };
+        assert!(webm(32767).is_ok());
+        assert!(webm(32768).is_err());
+
+        assert_eq!(q78_gain(-3.0).unwrap(), -768);
+        assert!(q78_gain(128.0).is_err());
+        assert!(q78_gain(f32::NAN).is_err());
+
+        let rotation = |rotation: &str| RecordingConfig { rotation: Some(rotation.to_string()), ..RecordingConfig::default() }.rotation();
+        assert_eq!(rotation("15").unwrap(), broadcast::Rotation::Every(Duration::from_secs(15 * 60)));
+        assert!(rotation("0").is_err());
+        assert!(rotation("hourly").is_err());
+    }
+}
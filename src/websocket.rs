@@ -0,0 +1,446 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use rocket::http::Status;
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use crate::access::{Access, Credentials, Denial};
+use crate::broadcast::OpenError;
+use crate::limits::Limiter;
+use crate::station::Stations;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE: usize = 8 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_PAYLOAD: u64 = 64 * 1024;
+
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Serves the streams over WebSocket, one binary message per page, at the same paths as over HTTP:
+/// `/stream`, `/stream/<tier>`, `/stations/<id>/stream` and `/stations/<id>/stream/<tier>`.
+/// The private stations take the token from the `token` query parameter or the `Authorization` header.
+/// Rocket has no support for connection upgrades, so this runs on a separate listener.
+pub async fn run(address: SocketAddr, stations: Stations, access: Access, limiter: Limiter) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let stations = stations.clone();
+        let access = access.clone();
+        let limiter = limiter.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve(socket, &stations, &access, &limiter).await {
+                eprintln!("websocket error: {}", e);
+            }
+        });
+    }
+}
+
+/// Station and tier of the path, the tier being the default one if there is none.
+fn route<'a>(path: &'a str, default: &'a str) -> Option<(&'a str, Option<&'a str>)> {
+    let segments: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return None;
+    }
+
+    match segments.as_slice() {
+        ["stream"] => Some((default, None)),
+        ["stream", tier] => Some((default, Some(tier))),
+        ["stations", id, "stream"] => Some((id, None)),
+        ["stations", id, "stream", tier] => Some((id, Some(tier))),
+        _ => None
+    }
+}
+
+async fn refuse<W: AsyncWrite + Unpin>(write: &mut W, status: Status, headers: &str) -> io::Result<()> {
+    let response = format!("HTTP/1.1 {} {}\r\n{}Connection: close\r\n\r\n", status.code, status.reason().unwrap_or(""), headers);
+    write.write_all(response.as_bytes()).await
+}
+
+async fn serve(socket: TcpStream, stations: &Stations, access: &Access, limiter: &Limiter) -> anyhow::Result<()> {
+    let address = socket.peer_addr().ok().map(|address| address.ip());
+    let (read, mut write) = socket.into_split();
+    let mut read = BufReader::new(read);
+
+    // a client that is slow to send the request does not get to hold the connection
+    let upgrade = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut read)).await {
+        Ok(result) => result?,
+        Err(_) => Err(HandshakeError::Timeout)
+    };
+
+    let upgrade = match upgrade {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            write.write_all(e.response().as_bytes()).await?;
+            return Ok(());
+        }
+    };
+
+    let (id, tier) = match route(&upgrade.path, stations.default_id()) {
+        Some(route) => route,
+        None => return Ok(refuse(&mut write, Status::NotFound, "").await?)
+    };
+
+    let station = match stations.get(id) {
+        Some(station) => station,
+        None => return Ok(refuse(&mut write, Status::NotFound, "").await?)
+    };
+
+    let grant = match station.private {
+        true => match Credentials::new(access, upgrade.token.clone()).verify(id) {
+            Ok(grant) => Some(grant),
+            Err(denial) => {
+                let status = if denial == Denial::Forbidden { Status::Forbidden } else { Status::Unauthorized };
+                return Ok(refuse(&mut write, status, "").await?);
+            }
+        },
+
+        false => None
+    };
+
+    // held until the connection is closed
+    let _permit = match limiter.admit(address) {
+        Ok(permit) => permit,
+        Err(rejection) => {
+            let retry = rejection.retry_after()
+                .map_or(String::new(), |retry| format!("Retry-After: {}\r\n", retry.as_secs()));
+
+            return Ok(refuse(&mut write, rejection.status(), &retry).await?);
+        }
+    };
+
+    let opened = match &grant {
+        Some(grant) => station.streams.open_as(&grant.holder, grant.max_listeners, tier, None, Duration::ZERO),
+        None => station.streams.open(tier)
+    };
+
+    let mut stream = match opened {
+        Ok(stream) => stream,
+        Err(OpenError::NoSuchTier(_)) => return Ok(refuse(&mut write, Status::NotFound, "").await?),
+        Err(OpenError::HolderLimit) => return Ok(refuse(&mut write, Status::TooManyRequests, "").await?),
+        Err(OpenError::Closed) => return Ok(refuse(&mut write, Status::ServiceUnavailable, "").await?)
+    };
+
+    stream.identify(address, None);
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&upgrade.key));
+
+    write.write_all(response.as_bytes()).await?;
+
+    // frames are read in a separate task, since reading them is not cancel safe
+    let (control, mut control_receiver) = unbounded_channel();
+    tokio::spawn(read_control_frames(read, control));
+
+    loop {
+        tokio::select! {
+            data = stream.next() => match data {
+                Some(data) => write_frame(&mut write, OPCODE_BINARY, &data).await?,
+                None => break
+            },
+
+            frame = control_receiver.recv() => match frame {
+                Some((OPCODE_PING, payload)) => write_frame(&mut write, OPCODE_PONG, &payload).await?,
+                Some((_, payload)) => {
+                    let _ = write_frame(&mut write, OPCODE_CLOSE, &payload).await;
+                    break;
+                },
+
+                None => break
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Upgrade request of a client.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+struct Upgrade {
+    path: String,
+    key: String,
+    token: Option<String>
+}
+
+/// Why a handshake has been refused.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+enum HandshakeError {
+    BadRequest,
+    /// Only version 13 (RFC 6455) is supported
+    UnsupportedVersion,
+    /// The request has not been received within `HANDSHAKE_TIMEOUT`
+    Timeout
+}
+
+impl HandshakeError {
+
+    fn response(&self) -> &'static str {
+        match self {
+            HandshakeError::BadRequest => "HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n",
+            HandshakeError::UnsupportedVersion => "HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\nConnection: close\r\n\r\n",
+            HandshakeError::Timeout => "HTTP/1.1 408 Request Timeout\r\nConnection: close\r\n\r\n"
+        }
+    }
+}
+
+/// Reads the upgrade request, checking that it is a valid websocket handshake (RFC 6455, section 4.2.1).
+async fn handshake<R: AsyncBufRead + Unpin>(read: &mut R) -> io::Result<Result<Upgrade, HandshakeError>> {
+    // no line can get past the limit, however long it is
+    let mut read = read.take(MAX_HANDSHAKE as u64);
+    let mut lines = Vec::new();
+
+    loop {
+        let mut line = String::new();
+
+        // a line without its end has been cut off, either by the limit or by the client
+        if read.read_line(&mut line).await? == 0 || !line.ends_with('\n') {
+            return Ok(Err(HandshakeError::BadRequest));
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        lines.push(line.to_string());
+    }
+
+    Ok(parse_upgrade(&lines))
+}
+
+fn parse_upgrade(lines: &[String]) -> Result<Upgrade, HandshakeError> {
+    let (request, headers) = lines.split_first().ok_or(HandshakeError::BadRequest)?;
+
+    let mut parts = request.split(' ');
+    let (method, target, version) = (parts.next(), parts.next(), parts.next());
+    if method != Some("GET") || version != Some("HTTP/1.1") || parts.next().is_some() {
+        return Err(HandshakeError::BadRequest);
+    }
+
+    let target = target.filter(|target| target.starts_with('/')).ok_or(HandshakeError::BadRequest)?;
+
+    let header = |name: &str| headers.iter()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim());
+
+    // both headers may list several values
+    let lists = |name: &str, token: &str| header(name)
+        .is_some_and(|value| value.split(',').any(|value| value.trim().eq_ignore_ascii_case(token)));
+
+    if !lists("upgrade", "websocket") || !lists("connection", "upgrade") {
+        return Err(HandshakeError::BadRequest);
+    }
+
+    // the key is a random 16 byte nonce
+    let key = header("sec-websocket-key")
+        .filter(|key| base64::decode(key).is_ok_and(|nonce| nonce.len() == 16))
+        .ok_or(HandshakeError::BadRequest)?;
+
+    match header("sec-websocket-version") {
+        Some("13") => {},
+        Some(_) => return Err(HandshakeError::UnsupportedVersion),
+        None => return Err(HandshakeError::BadRequest)
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let token = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "token")
+        .map(|(_, token)| token.into_owned())
+        .or_else(|| header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string()));
+
+    Ok(Upgrade {
+        path: path.to_string(),
+        key: key.to_string(),
+        token
+    })
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = sha1::Sha1::from(format!("{}{}", key, GUID)).digest();
+    base64::encode(digest.bytes())
+}
+
+/// Forwards the control frames (ping, close) sent by the client; data frames are ignored.
+async fn read_control_frames<R: AsyncRead + Unpin>(mut read: R, control: UnboundedSender<(u8, Vec<u8>)>) {
+    while let Ok((opcode, payload)) = read_frame(&mut read).await {
+        if opcode != OPCODE_PING && opcode != OPCODE_CLOSE {
+            continue;
+        }
+
+        if control.send((opcode, payload)).is_err() || opcode == OPCODE_CLOSE {
+            break;
+        }
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(read: &mut R) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    read.read_exact(&mut head).await?;
+
+    let opcode = head[0] & 0x0F;
+    let masked = head[1] & 0x80 != 0;
+    let length = match head[1] & 0x7F {
+        126 => read.read_u16().await? as u64,
+        127 => read.read_u64().await?,
+        n => n as u64
+    };
+
+    if length > MAX_PAYLOAD {
+        return Err(anyhow::Error::msg("websocket frame is too large"));
+    }
+
+    let mut mask = [0u8; 4];
+    if masked {
+        read.read_exact(&mut mask).await?;
+    }
+
+    let mut payload = vec![0u8; length as usize];
+    read.read_exact(&mut payload).await?;
+
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+
+    Ok((opcode, payload))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(write: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = Vec::with_capacity(10);
+    header.push(0x80 | opcode);
+
+    match payload.len() {
+        n if n < 126 => header.push(n as u8),
+        n if n <= 0xFFFF => {
+            header.push(126);
+            header.extend_from_slice(&(n as u16).to_be_bytes());
+        },
+
+        n => {
+            header.push(127);
+            header.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+
+    write.write_all(&header).await?;
+    write.write_all(payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &str = "GET /stream/packets?token=abc HTTP/1.1\r\n\
+        Host: localhost:8001\r\n\
+        Upgrade: websocket\r\n\
+        Connection: keep-alive, Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+
+    async fn parse(request: &str) -> Result<Upgrade, HandshakeError> {
+        handshake(&mut request.as_bytes()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_a_valid_upgrade() {
+        let upgrade = parse(REQUEST).await.unwrap();
+
+        assert_eq!(upgrade.path, "/stream/packets");
+        assert_eq!(upgrade.token.as_deref(), Some("abc"));
+        assert_eq!(accept_key(&upgrade.key), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn takes_the_token_from_the_header_too() {
+        let request = REQUEST
+            .replace("/stream/packets?token=abc", "/stream/packets")
+            .replace("Host:", "Authorization: Bearer xyz\r\nHost:");
+
+        assert_eq!(parse(&request).await.unwrap().token.as_deref(), Some("xyz"));
+    }
+
+    #[test]
+    fn routes_like_the_http_server() {
+        assert_eq!(route("/stream", "main"), Some(("main", None)));
+        assert_eq!(route("/stream/packets", "main"), Some(("main", Some("packets"))));
+        assert_eq!(route("/stations/jazz/stream", "main"), Some(("jazz", None)));
+        assert_eq!(route("/stations/jazz/stream/low", "main"), Some(("jazz", Some("low"))));
+
+        for path in ["/", "/stream/", "/stream/packets/more", "/stations/jazz", "/events"] {
+            assert_eq!(route(path, "main"), None, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_what_is_not_an_upgrade() {
+        let cases = [
+            REQUEST.replace("GET", "POST"),
+            REQUEST.replace("HTTP/1.1", "HTTP/1.0"),
+            REQUEST.replace("GET /stream/packets?token=abc", "GET *"),
+            REQUEST.replace("Upgrade: websocket", "Upgrade: h2c"),
+            REQUEST.replace("Connection: keep-alive, Upgrade", "Connection: keep-alive"),
+            REQUEST.replace("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n", ""),
+            REQUEST.replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ="),
+            REQUEST.replace("Sec-WebSocket-Version: 13\r\n", ""),
+            // cut off before the end of the headers
+            REQUEST.trim_end().to_string(),
+            "x".repeat(MAX_HANDSHAKE + 1),
+            format!("{}{}\r\n\r\n", REQUEST.trim_end(), "\r\nX-Padding: x".repeat(MAX_HANDSHAKE / 10))
+        ];
+
+        for case in cases.iter() {
+            assert_eq!(parse(case).await, Err(HandshakeError::BadRequest), "{:?}", case);
+        }
+    }
+
+    #[tokio::test]
+    async fn endless_lines_are_cut_off() {
+        let mut read = BufReader::new(tokio::io::repeat(b'x'));
+        assert_eq!(handshake(&mut read).await.unwrap(), Err(HandshakeError::BadRequest));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_versions() {
+        let request = REQUEST.replace("Sec-WebSocket-Version: 13", "Sec-WebSocket-Version: 8");
+
+        assert_eq!(parse(&request).await, Err(HandshakeError::UnsupportedVersion));
+        assert!(HandshakeError::UnsupportedVersion.response().contains("Sec-WebSocket-Version: 13"));
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        for length in [0, 125, 126, 0xFFFF, 0x10000] {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+
+            let mut buffer = Vec::new();
+            write_frame(&mut buffer, OPCODE_BINARY, &payload).await.unwrap();
+
+            if length <= MAX_PAYLOAD as usize {
+                let (opcode, read) = read_frame(&mut buffer.as_slice()).await.unwrap();
+                assert_eq!((opcode, read), (OPCODE_BINARY, payload));
+            } else {
+                assert!(read_frame(&mut buffer.as_slice()).await.is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn masked_frames_are_unmasked() {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+        let mut frame = vec![0x89, 0x80 | 5];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"Hello".iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+
+        let (opcode, payload) = read_frame(&mut frame.as_slice()).await.unwrap();
+        assert_eq!((opcode, payload.as_slice()), (OPCODE_PING, b"Hello".as_slice()));
+    }
+}